@@ -4,11 +4,13 @@ extern crate rand;
 extern crate syscall;
 
 use rand::{Rng, OsRng};
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
 use std::cell::RefCell;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io::{self, Read, Write};
-use std::{mem, process, slice, str};
+use std::{cmp, mem, process, ptr, slice, str};
 use std::ops::{Deref, DerefMut};
 use std::os::unix::io::FromRawFd;
 use std::rc::Rc;
@@ -17,10 +19,14 @@ use event::EventQueue;
 use netutils::{n16, n32, Ipv4, Ipv4Addr, Ipv4Header, Checksum};
 use netutils::tcp::{Tcp, TcpHeader, TCP_FIN, TCP_SYN, TCP_RST, TCP_PSH, TCP_ACK};
 use syscall::data::{Packet, TimeSpec};
-use syscall::error::{Error, Result, EACCES, EADDRINUSE, EBADF, EIO, EINVAL, EISCONN, EMSGSIZE, ENOTCONN, ETIMEDOUT, EWOULDBLOCK};
-use syscall::flag::{CLOCK_MONOTONIC, EVENT_READ, F_GETFL, F_SETFL, O_ACCMODE, O_CREAT, O_RDWR, O_NONBLOCK};
+use syscall::error::{Error, Result, EACCES, EADDRINUSE, EBADF, ECONNABORTED, ECONNREFUSED, ECONNRESET, EINPROGRESS, EIO, EINVAL, EISCONN, ENOTCONN, EPIPE, ETIMEDOUT, EWOULDBLOCK};
+use syscall::flag::{CLOCK_MONOTONIC, EVENT_READ, EVENT_WRITE, F_GETFL, F_SETFL, O_ACCMODE, O_CREAT, O_RDWR, O_NONBLOCK};
 use syscall::scheme::SchemeMut;
 
+use timer::{TimerKind, Timers};
+
+mod timer;
+
 fn add_time(a: &TimeSpec, b: &TimeSpec) -> TimeSpec {
     let mut secs = a.tv_sec + b.tv_sec;
 
@@ -36,6 +42,367 @@ fn add_time(a: &TimeSpec, b: &TimeSpec) -> TimeSpec {
     }
 }
 
+fn millis(ms: u64) -> TimeSpec {
+    TimeSpec {
+        tv_sec: (ms / 1000) as i64,
+        tv_nsec: ((ms % 1000) * 1000000) as i32
+    }
+}
+
+fn time_reached(time: &TimeSpec, deadline: &TimeSpec) -> bool {
+    time.tv_sec > deadline.tv_sec || (time.tv_sec == deadline.tv_sec && time.tv_nsec >= deadline.tv_nsec)
+}
+
+fn to_millis(time: &TimeSpec) -> u64 {
+    time.tv_sec as u64 * 1000 + time.tv_nsec as u64 / 1000000
+}
+
+fn to_micros(time: &TimeSpec) -> u64 {
+    time.tv_sec as u64 * 1000000 + time.tv_nsec as u64 / 1000
+}
+
+fn monotonic() -> Result<TimeSpec> {
+    let mut time = TimeSpec::default();
+    syscall::clock_gettime(CLOCK_MONOTONIC, &mut time)?;
+    Ok(time)
+}
+
+/// Check if sequence number `a` comes before `b`, allowing for wraparound
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Check if sequence number `a` comes before or is `b`, allowing for wraparound
+fn seq_leq(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+/// Check if sequence number `a` comes after `b`, allowing for wraparound
+fn seq_gt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// Check if a sequence number falls in the window of `len` numbers starting at `start`
+fn seq_in_window(seq: u32, start: u32, len: u32) -> bool {
+    seq_leq(start, seq) && seq_lt(seq, start.wrapping_add(len))
+}
+
+/// The options of a segment as kind and value pairs. Iteration stops at the end of the
+/// option list, or at the first option whose length is too short or runs past the header
+struct Options<'a> {
+    options: &'a [u8],
+    i: usize
+}
+
+impl<'a> Options<'a> {
+    fn new(options: &'a [u8]) -> Self {
+        Options {
+            options,
+            i: 0
+        }
+    }
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.i < self.options.len() {
+            match self.options[self.i] {
+                // End of option list
+                0 => break,
+                // No-operation
+                1 => self.i += 1,
+                kind => {
+                    let len = self.options.get(self.i + 1).map_or(0, |&len| len as usize);
+                    if len < 2 || self.i + len > self.options.len() {
+                        break;
+                    }
+
+                    let value = &self.options[self.i + 2 .. self.i + len];
+                    self.i += len;
+                    return Some((kind, value));
+                }
+            }
+        }
+
+        self.i = self.options.len();
+        None
+    }
+}
+
+/// Find the value of an option, if present
+fn find_option(options: &[u8], kind: u8) -> Option<&[u8]> {
+    Options::new(options).find(|&(option_kind, _)| option_kind == kind).map(|(_, value)| value)
+}
+
+/// Check that the data offset of a segment leaves room for the header and stays inside the segment
+fn data_offset_ok(bytes: &[u8]) -> bool {
+    if bytes.len() < mem::size_of::<TcpHeader>() {
+        return false;
+    }
+
+    let offset = (bytes[12] >> 4) as usize * 4;
+    offset >= mem::size_of::<TcpHeader>() && offset <= bytes.len()
+}
+
+/// Check that a segment's flags make sense together. A SYN cannot also end or reset the
+/// connection, a RST with a FIN needs an ACK to be believed, and a segment with no flags must
+/// at least carry data
+fn flags_ok(tcp: &Tcp) -> bool {
+    let flags = tcp.header.flags.get();
+    (flags & TCP_SYN != TCP_SYN || flags & (TCP_FIN | TCP_RST) == 0)
+        && flags & (TCP_RST | TCP_FIN | TCP_ACK) != TCP_RST | TCP_FIN
+        && (flags & (TCP_SYN | TCP_FIN | TCP_RST | TCP_PSH | TCP_ACK) != 0 || ! tcp.data.is_empty())
+}
+
+/// Find the maximum segment size option, if present
+fn parse_mss(options: &[u8]) -> Option<u16> {
+    let value = find_option(options, 2)?;
+    if value.len() != 2 {
+        return None;
+    }
+
+    let mss = (value[0] as u16) << 8 | value[1] as u16;
+    if mss > 0 {
+        Some(mss)
+    } else {
+        None
+    }
+}
+
+/// Find the window scale option, if present
+fn parse_wscale(options: &[u8]) -> Option<u8> {
+    let value = find_option(options, 3)?;
+    if value.len() != 1 {
+        return None;
+    }
+
+    // Shifts beyond 14 are treated as 14 (RFC 1323 section 2.3)
+    Some(cmp::min(value[0], WSCALE_MAX))
+}
+
+/// Our maximum segment size, leaving room for the IP and TCP headers in one MTU
+fn local_mss() -> u16 {
+    (MTU - mem::size_of::<Ipv4Header>() - mem::size_of::<TcpHeader>()) as u16
+}
+
+/// Maximum segment size to send with, from the peer's option but never more than our own MTU allows
+fn send_mss(options: &[u8]) -> u16 {
+    cmp::min(parse_mss(options).unwrap_or(DEFAULT_MSS), local_mss())
+}
+
+/// Maximum segment size asked for through the maxseg setting, kept within what we can use
+fn clamp_mss(size: usize) -> u16 {
+    cmp::max(cmp::min(size, local_mss() as usize) as u16, MSS_MIN)
+}
+
+/// Encode a maximum segment size option
+fn mss_option(mss: u16) -> [u8; 4] {
+    [2, 4, (mss >> 8) as u8, mss as u8]
+}
+
+/// Our window scale, the smallest shift that lets the receive buffer be advertised
+fn local_wscale() -> u8 {
+    let mut shift = 0;
+    while shift < WSCALE_MAX && RECEIVE_BUFFER >> shift > u16::MAX as usize {
+        shift += 1;
+    }
+    shift
+}
+
+/// Encode a window scale option, padded with a no-operation
+fn wscale_option(shift: u8) -> [u8; 4] {
+    [1, 3, 3, shift]
+}
+
+/// Check for the SACK permitted option
+fn parse_sack_permitted(options: &[u8]) -> bool {
+    find_option(options, 4).is_some_and(|value| value.is_empty())
+}
+
+/// Encode a SACK permitted option, padded with two no-operations
+fn sack_permitted_option() -> [u8; 4] {
+    [1, 1, 4, 2]
+}
+
+/// Find the blocks of a SACK option, if present, as the sequence numbers they start and end at
+fn parse_sack(options: &[u8]) -> Vec<(u32, u32)> {
+    let value = match find_option(options, 5) {
+        Some(value) if value.len() % 8 == 0 => value,
+        _ => return Vec::new()
+    };
+
+    value.chunks(8).map(|block| {
+        let left = (block[0] as u32) << 24 | (block[1] as u32) << 16 | (block[2] as u32) << 8 | block[3] as u32;
+        let right = (block[4] as u32) << 24 | (block[5] as u32) << 16 | (block[6] as u32) << 8 | block[7] as u32;
+        (left, right)
+    }).collect()
+}
+
+/// Encode a SACK option for the first few blocks, padded with two no-operations
+fn sack_option(blocks: &VecDeque<(u32, Vec<u8>)>) -> Vec<u8> {
+    let count = cmp::min(blocks.len(), SACK_BLOCKS);
+
+    let mut option = vec![1, 1, 5, (2 + 8 * count) as u8];
+    for &(start, ref data) in blocks.iter().take(count) {
+        let end = start.wrapping_add(data.len() as u32);
+        for edge in [start, end].iter() {
+            option.extend_from_slice(&[(edge >> 24) as u8, (edge >> 16) as u8, (edge >> 8) as u8, *edge as u8]);
+        }
+    }
+    option
+}
+
+/// Find the timestamps option, if present, as the sender's value and the value it echoes
+fn parse_timestamps(options: &[u8]) -> Option<(u32, u32)> {
+    let value = find_option(options, 8)?;
+    if value.len() != 8 {
+        return None;
+    }
+
+    let tsval = (value[0] as u32) << 24 | (value[1] as u32) << 16 | (value[2] as u32) << 8 | value[3] as u32;
+    let tsecr = (value[4] as u32) << 24 | (value[5] as u32) << 16 | (value[6] as u32) << 8 | value[7] as u32;
+    Some((tsval, tsecr))
+}
+
+/// Encode a timestamps option, padded with two no-operations
+fn timestamps_option(tsval: u32, tsecr: u32) -> [u8; 12] {
+    [
+        1, 1, 8, 10,
+        (tsval >> 24) as u8, (tsval >> 16) as u8, (tsval >> 8) as u8, tsval as u8,
+        (tsecr >> 24) as u8, (tsecr >> 16) as u8, (tsecr >> 8) as u8, tsecr as u8
+    ]
+}
+
+/// Our timestamp clock, counting milliseconds of monotonic time
+fn timestamp(time: &TimeSpec) -> u32 {
+    to_millis(time) as u32
+}
+
+/// Initial sequence number for a connection (RFC 6528 section 3). A clock ticking every 4
+/// microseconds keeps successive connections on the same addresses from reusing sequence numbers,
+/// and a keyed hash of the addresses keeps other connections from predicting it
+fn initial_sequence(secret: u64, local: (Ipv4Addr, u16), remote: (Ipv4Addr, u16), time: &TimeSpec) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    secret.hash(&mut hasher);
+    local.0.bytes.hash(&mut hasher);
+    local.1.hash(&mut hasher);
+    remote.0.bytes.hash(&mut hasher);
+    remote.1.hash(&mut hasher);
+    ((to_micros(time) / 4) as u32).wrapping_add(hasher.finish() as u32)
+}
+
+/// A reset answering a segment that belongs to no connection (RFC 793 section 3.4)
+fn create_reset(ip: &Ipv4, tcp: &Tcp, id: u16) -> Ipv4 {
+    // The reset takes its sequence number from the ACK if there is one, otherwise it acknowledges the segment
+    let (seq, ack, flags) = if tcp.header.flags.get() & TCP_ACK == TCP_ACK {
+        (tcp.header.ack_num.get(), 0, TCP_RST)
+    } else {
+        let mut len = tcp.data.len() as u32;
+        if tcp.header.flags.get() & TCP_SYN == TCP_SYN {
+            len += 1;
+        }
+        if tcp.header.flags.get() & TCP_FIN == TCP_FIN {
+            len += 1;
+        }
+        (0, tcp.header.sequence.get().wrapping_add(len), TCP_RST | TCP_ACK)
+    };
+
+    let reset = Tcp {
+        header: TcpHeader {
+            src: tcp.header.dst,
+            dst: tcp.header.src,
+            sequence: n32::new(seq),
+            ack_num: n32::new(ack),
+            flags: n16::new(((mem::size_of::<TcpHeader>() << 10) & 0xF000) as u16 | flags),
+            window_size: n16::new(0),
+            checksum: Checksum { data: 0 },
+            urgent_pointer: n16::new(0),
+        },
+        options: Vec::new(),
+        data: Vec::new()
+    };
+
+    let data = reset.to_bytes();
+    Ipv4 {
+        header: Ipv4Header {
+            ver_hlen: 0x45,
+            services: 0,
+            len: n16::new((data.len() + mem::size_of::<Ipv4Header>()) as u16),
+            id: n16::new(id),
+            flags_fragment: n16::new(0),
+            ttl: 64,
+            proto: 0x06,
+            checksum: Checksum { data: 0 },
+            src: ip.header.dst,
+            dst: ip.header.src
+        },
+        options: Vec::new(),
+        data
+    }
+}
+
+/// A SYN-ACK answering a SYN for which no state is kept, with a cookie as its sequence number and
+/// only the MSS option, since that is all the cookie can remember
+fn create_cookie_syn_ack(ip: &Ipv4, tcp: &Tcp, cookie: u32, mss: u16, ttl: u8, id: u16) -> Ipv4 {
+    let options = mss_option(mss).to_vec();
+    let syn_ack = Tcp {
+        header: TcpHeader {
+            src: tcp.header.dst,
+            dst: tcp.header.src,
+            sequence: n32::new(cookie),
+            ack_num: n32::new(tcp.header.sequence.get().wrapping_add(1)),
+            flags: n16::new((((mem::size_of::<TcpHeader>() + options.len()) << 10) & 0xF000) as u16 | TCP_SYN | TCP_ACK),
+            // Without the window scale option the window cannot be scaled later either
+            window_size: n16::new(cmp::min(RECEIVE_BUFFER, u16::MAX as usize) as u16),
+            checksum: Checksum { data: 0 },
+            urgent_pointer: n16::new(0),
+        },
+        options,
+        data: Vec::new()
+    };
+
+    let data = syn_ack.to_bytes();
+    Ipv4 {
+        header: Ipv4Header {
+            ver_hlen: 0x45,
+            services: 0,
+            len: n16::new((data.len() + mem::size_of::<Ipv4Header>()) as u16),
+            id: n16::new(id),
+            flags_fragment: n16::new(IP_DF),
+            ttl,
+            proto: 0x06,
+            checksum: Checksum { data: 0 },
+            src: ip.header.dst,
+            dst: ip.header.src
+        },
+        options: Vec::new(),
+        data
+    }
+}
+
+/// Check the IPv4 header checksum
+fn ip_checksum_ok(ip: &Ipv4) -> bool {
+    Checksum::compile(unsafe {
+        Checksum::sum((&ip.header as *const Ipv4Header) as usize, mem::size_of::<Ipv4Header>()) +
+        Checksum::sum(ip.options.as_ptr() as usize, ip.options.len())
+    }) == 0
+}
+
+/// Check the TCP checksum, which also covers a pseudo header of the IPv4 addresses, protocol and length
+fn tcp_checksum_ok(ip: &Ipv4) -> bool {
+    let proto = n16::new(0x06);
+    let segment_len = n16::new(ip.data.len() as u16);
+    Checksum::compile(unsafe {
+        Checksum::sum(ip.header.src.bytes.as_ptr() as usize, ip.header.src.bytes.len()) +
+        Checksum::sum(ip.header.dst.bytes.as_ptr() as usize, ip.header.dst.bytes.len()) +
+        Checksum::sum((&proto as *const n16) as usize, mem::size_of::<n16>()) +
+        Checksum::sum((&segment_len as *const n16) as usize, mem::size_of::<n16>()) +
+        Checksum::sum(ip.data.as_ptr() as usize, ip.data.len())
+    }) == 0
+}
+
 fn parse_socket(socket: &str) -> (Ipv4Addr, u16) {
     let mut socket_parts = socket.split(":");
     let host = Ipv4Addr::from_str(socket_parts.next().unwrap_or(""));
@@ -43,10 +410,91 @@ fn parse_socket(socket: &str) -> (Ipv4Addr, u16) {
     (host, port)
 }
 
+/// Initial retransmission timeout, in milliseconds
+const RTO_INITIAL: u64 = 1000;
+/// Initial retransmission timeout for a SYN or SYN-ACK, in milliseconds
+const SYN_RTO_INITIAL: u64 = 3000;
+/// Lower bound for the computed retransmission timeout, in milliseconds
+const RTO_MIN: u64 = 200;
+/// Default upper bound for the retransmission timeout after backoff, in milliseconds
+const RTO_MAX: u64 = 60000;
+/// Number of times a SYN is retransmitted before the connect fails
+const SYN_RETRIES: u8 = 5;
+/// Number of times data or a FIN is retransmitted without progress before the connection is dropped
+const RETRIES: u8 = 15;
+/// Maximum segment size assumed when the peer does not send the option
+const DEFAULT_MSS: u16 = 536;
+/// Congestion window at the start of a connection, in segments
+const INITIAL_CWND: u32 = 2;
+/// Maximum transmission unit of the interface
+const MTU: usize = 1500;
+/// Bytes of received data buffered for the application before the window closes
+const RECEIVE_BUFFER: usize = 262144;
+/// Default bytes of written data buffered before writes block
+const SEND_BUFFER: usize = 262144;
+/// Most SACK blocks reported in one segment
+const SACK_BLOCKS: usize = 3;
+/// Most resets sent per second for segments that belong to no connection
+const RESET_LIMIT: u32 = 100;
+/// Most ACKs sent per second for segments outside the receive window
+const ACK_LIMIT: u32 = 100;
+/// Segments SACKed past an unacknowledged one before it is taken as lost
+const DUP_THRESH: usize = 3;
+/// Default number of SYNs a listening handle queues before new ones are dropped
+const BACKLOG: usize = 128;
+/// Half open connections, queued or in SynReceived, beyond which new SYNs are answered with a cookie
+const HALF_OPEN_LIMIT: usize = 1024;
+/// How long a SYN cookie secret is used for new cookies, in milliseconds. Cookies made with the
+/// previous secret are still accepted, so a cookie lasts between one and two periods
+const COOKIE_PERIOD: u64 = 64000;
+/// Maximum segment sizes a SYN cookie can encode, by index
+const COOKIE_MSS: [u16; 8] = [MSS_MIN, 536, 1024, 1220, 1300, 1400, 1440, 1460];
+/// Largest window scale shift allowed
+const WSCALE_MAX: u8 = 14;
+/// Time an ACK for received data may be held back, in milliseconds
+const DELAYED_ACK: u64 = 40;
+/// Time a closed connection lingers in TimeWait (twice the maximum segment lifetime), in milliseconds
+const TIME_WAIT: u64 = 60000;
+/// Time a half closed connection waits for the peer's FIN before it is dropped, in milliseconds
+const FIN_WAIT2: u64 = 60000;
+/// Time after which the peer's last timestamp is too old for PAWS, in milliseconds
+const PAWS_IDLE: u32 = 24 * 24 * 60 * 60 * 1000;
+/// Time a connection with keepalive enabled may be idle before it is probed, in milliseconds
+const KEEPALIVE_IDLE: u64 = 7200000;
+/// Time between unanswered keepalive probes, in milliseconds
+const KEEPALIVE_INTERVAL: u64 = 75000;
+/// Unanswered keepalive probes before the connection is dropped
+const KEEPALIVE_PROBES: u8 = 9;
+/// Values written to the shutdown setting, as for shutdown(2)
+const SHUT_RD: u8 = 0;
+const SHUT_WR: u8 = 1;
+const SHUT_RDWR: u8 = 2;
+/// ECN echo and congestion window reduced, the flags beyond those netutils defines
+const TCP_ECE: u16 = 0x40;
+const TCP_CWR: u16 = 0x80;
+/// Urgent pointer significant, also missing from netutils
+const TCP_URG: u16 = 0x20;
+/// Don't fragment, so a segment too large for the path is reported instead of split
+const IP_DF: u16 = 0x4000;
+/// Smallest MSS the maxseg setting accepts
+const MSS_MIN: u16 = 88;
+/// Smallest MTU every IPv4 link must carry
+const MTU_MIN: usize = 68;
+/// ECN codepoints in the low bits of the IPv4 services field
+const IP_ECN: u8 = 0x03;
+const IP_ECT0: u8 = 0x02;
+const IP_CE: u8 = 0x03;
+
+/// Local address, local port, remote address and remote port of a connection
+type ConnectionKey = ([u8; 4], u16, [u8; 4], u16);
+
 #[derive(Debug)]
 struct EmptyHandle {
     privileged: bool,
-    flags: usize
+    uid: u32,
+    flags: usize,
+    reuse_addr: bool,
+    max_seg: u16
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -64,31 +512,283 @@ enum State {
     Closed
 }
 
+impl State {
+    /// Name of the state as reported to applications
+    fn name(&self) -> &'static str {
+        match *self {
+            State::Listen => "listen",
+            State::SynSent => "syn_sent",
+            State::SynReceived => "syn_received",
+            State::Established => "established",
+            State::FinWait1 => "fin_wait1",
+            State::FinWait2 => "fin_wait2",
+            State::CloseWait => "close_wait",
+            State::Closing => "closing",
+            State::LastAck => "last_ack",
+            State::TimeWait => "time_wait",
+            State::Closed => "closed"
+        }
+    }
+}
+
+/// A segment that has been sent but not yet acknowledged
+#[derive(Debug)]
+struct Segment {
+    seq: u32,
+    flags: u16,
+    data: Vec<u8>,
+    sent: TimeSpec,
+    retransmitted: bool,
+    /// The peer reported it in a SACK block, so it arrived even though it is not acknowledged yet
+    sacked: bool,
+}
+
+impl Segment {
+    /// Sequence number following this segment, counting SYN and FIN
+    fn end(&self) -> u32 {
+        let mut len = self.data.len() as u32;
+        if self.flags & TCP_SYN == TCP_SYN {
+            len += 1;
+        }
+        if self.flags & TCP_FIN == TCP_FIN {
+            len += 1;
+        }
+        self.seq.wrapping_add(len)
+    }
+}
+
+/// What close does with data still unacknowledged, as given to the linger setting. Laid out like
+/// a C `struct linger`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+struct Linger {
+    /// Nonzero for close to wait, or to reset the connection if `seconds` is zero
+    enabled: u32,
+    /// Time close waits for sent data to be acknowledged before resetting the connection
+    seconds: u32
+}
+
 #[derive(Debug)]
 struct TcpHandle {
     local: (Ipv4Addr, u16),
     remote: (Ipv4Addr, u16),
+    /// User that opened the connection, or the listener it was accepted from
+    uid: u32,
     flags: usize,
     events: usize,
     read_timeout: Option<TimeSpec>,
     write_timeout: Option<TimeSpec>,
     ttl: u8,
     state: State,
-    seq: u32,
-    ack: u32,
+    /// Oldest sequence number not yet acknowledged by the peer
+    snd_una: u32,
+    /// Next sequence number to send
+    snd_nxt: u32,
+    /// Next sequence number expected from the peer, sent as our acknowledgment number
+    rcv_nxt: u32,
     data: VecDeque<(Ipv4, Tcp)>,
     todo_dup: VecDeque<Packet>,
     todo_read: VecDeque<(Option<TimeSpec>, Packet)>,
     todo_write: VecDeque<(Option<TimeSpec>, Packet)>,
+    /// Closes waiting for the data sent before them to be acknowledged
+    todo_close: VecDeque<Packet>,
+    /// Fsyncs waiting for everything written before them to be acknowledged
+    todo_sync: VecDeque<Packet>,
+    unacked: VecDeque<Segment>,
+    /// Data written by the application but not sent yet
+    send_buf: VecDeque<u8>,
+    /// Bytes of written data buffered before writes block
+    send_buffer: usize,
+    /// SYNs a listening handle queues before new ones are dropped
+    backlog: usize,
+    rto: u64,
+    /// Smoothed round trip time, in microseconds
+    srtt: Option<u64>,
+    /// Round trip time variance, in microseconds
+    rttvar: u64,
+    retries: u8,
+    syn_retries: u8,
+    syn_rto: u64,
+    /// Retransmissions of data or a FIN without progress before the connection is dropped
+    max_retries: u8,
+    /// Upper bound for the retransmission timeout after backoff, in milliseconds
+    rto_max: u64,
+    /// Time spent in TimeWait, in milliseconds
+    time_wait: u64,
+    /// Time to wait for the peer's FIN after ours was acknowledged, in milliseconds
+    fin_wait2: u64,
+    /// What close does with data still unacknowledged, returning at once by default
+    linger: Linger,
+    remote_mss: u16,
+    /// Largest segment we advertise and send, lowered by the maxseg setting
+    max_seg: u16,
+    /// Congestion window, in bytes
+    cwnd: u32,
+    /// Slow start threshold, in bytes
+    ssthresh: u32,
+    /// Send small segments right away instead of waiting for outstanding data to be acknowledged
+    nodelay: bool,
+    /// Request ECN when connecting
+    ecn: bool,
+    /// Allow the local port to be shared, see Tcpd::port_reusable
+    reuse_addr: bool,
+    /// Probe the peer when the connection is idle
+    keepalive: bool,
+    /// Let a read continue across segments up to the next one the peer pushed
+    push_boundaries: bool,
+    /// Keepalive probes sent since the peer was last heard from
+    keepalive_probes: u8,
+    /// ECN was agreed on, on our request or on the peer's
+    ecn_ok: bool,
+    /// Congestion was marked on packets from the peer, so our ACKs carry ECE until it sends CWR
+    ece: bool,
+    /// We reduced the congestion window for an ECE, so the next new data carries CWR
+    cwr: bool,
+    /// An ECE only reduces the congestion window again once data sent after the last reduction is acknowledged
+    ecn_recover: Option<u32>,
+    /// Received data has not been acknowledged yet, the delayed ACK timer is running
+    ack_pending: bool,
+    /// Receive window currently offered to the peer, in bytes
+    rcv_wnd: u32,
+    /// Why a connect failed, for the next read or write when nothing was waiting on it
+    error: Option<i32>,
+    /// Shift applied to the window we advertise, if scaling was offered or agreed
+    rcv_wscale: Option<u8>,
+    /// Shift applied to the window the peer advertises
+    snd_wscale: u8,
+    /// Receive window of the peer, in bytes
+    snd_wnd: u32,
+    /// Sequence and acknowledgement numbers of the segment that last updated snd_wnd
+    snd_wl1: u32,
+    snd_wl2: u32,
+    /// Largest window the peer has offered, in bytes
+    max_snd_wnd: u32,
+    /// Window probes sent since the peer's window closed
+    probes: u8,
+    /// Bytes of new data sent, not counting retransmissions
+    bytes_sent: u64,
+    /// Bytes of data received in order
+    bytes_received: u64,
+    /// Segments retransmitted
+    retransmits: u64,
+    /// Both sides send the timestamps option
+    timestamps: bool,
+    /// Latest timestamp from the peer, echoed back in ours
+    ts_recent: u32,
+    /// Our own timestamp when ts_recent was taken
+    ts_recent_age: u32,
+    /// The peer accepts SACK options
+    sack: bool,
+    /// Losses found through SACK only reduce the congestion window again once data sent after the last reduction is acknowledged
+    recover: Option<u32>,
+    /// Data received past a gap, as disjoint blocks with the most recently changed first
+    reassembly: VecDeque<(u32, Vec<u8>)>,
+    /// Sequence number after the last urgent byte written, until it is acknowledged
+    snd_up: Option<u32>,
+    /// Urgent byte received out of band, waiting to be read through the oob path
+    oob: Option<u8>,
+    /// The application has shut down reading, so arriving data is acknowledged and dropped
+    read_shutdown: bool,
+    /// The application has closed the handle and only the peer is left to finish the connection
+    closed: bool,
 }
 
 impl TcpHandle {
+    fn new(local: (Ipv4Addr, u16), remote: (Ipv4Addr, u16), flags: usize) -> TcpHandle {
+        TcpHandle {
+            local,
+            remote,
+            flags,
+            uid: 0,
+            events: 0,
+            read_timeout: None,
+            write_timeout: None,
+            ttl: 64,
+            state: State::Listen,
+            snd_nxt: 0,
+            snd_una: 0,
+            rcv_nxt: 0,
+            data: VecDeque::new(),
+            todo_dup: VecDeque::new(),
+            todo_read: VecDeque::new(),
+            todo_write: VecDeque::new(),
+            todo_close: VecDeque::new(),
+            todo_sync: VecDeque::new(),
+            unacked: VecDeque::new(),
+            send_buf: VecDeque::new(),
+            send_buffer: SEND_BUFFER,
+            backlog: BACKLOG,
+            rto: RTO_INITIAL,
+            srtt: None,
+            rttvar: 0,
+            retries: 0,
+            syn_retries: SYN_RETRIES,
+            syn_rto: SYN_RTO_INITIAL,
+            max_retries: RETRIES,
+            rto_max: RTO_MAX,
+            time_wait: TIME_WAIT,
+            fin_wait2: FIN_WAIT2,
+            linger: Linger::default(),
+            remote_mss: DEFAULT_MSS,
+            max_seg: local_mss(),
+            cwnd: INITIAL_CWND * DEFAULT_MSS as u32,
+            ssthresh: u32::MAX,
+            nodelay: false,
+            ecn: false,
+            reuse_addr: false,
+            keepalive: false,
+            push_boundaries: false,
+            keepalive_probes: 0,
+            ecn_ok: false,
+            ece: false,
+            cwr: false,
+            ecn_recover: None,
+            ack_pending: false,
+            error: None,
+            rcv_wnd: RECEIVE_BUFFER as u32,
+            rcv_wscale: Some(local_wscale()),
+            snd_wscale: 0,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            max_snd_wnd: 0,
+            probes: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            retransmits: 0,
+            sack: false,
+            recover: None,
+            timestamps: false,
+            ts_recent: 0,
+            ts_recent_age: 0,
+            reassembly: VecDeque::new(),
+            snd_up: None,
+            oob: None,
+            read_shutdown: false,
+            closed: false,
+        }
+    }
+
     fn is_connected(&self) -> bool {
         self.remote.0 != Ipv4Addr::NULL && self.remote.1 != 0
     }
 
+    /// Connections this handle holds half open, counted against `HALF_OPEN_LIMIT`: itself while
+    /// in SynReceived, or the SYNs a listener has queued
+    fn half_open(&self) -> usize {
+        match self.state {
+            State::SynReceived => 1,
+            State::Listen => self.data.len(),
+            _ => 0
+        }
+    }
+
     fn read_closed(&self) -> bool {
-        self.state == State::CloseWait || self.state == State::LastAck || self.state == State::TimeWait || self.state == State::Closed
+        self.read_shutdown || self.state == State::CloseWait || self.state == State::Closing || self.state == State::LastAck || self.state == State::TimeWait || self.state == State::Closed
+    }
+
+    fn key(&self) -> ConnectionKey {
+        (self.local.0.bytes, self.local.1, self.remote.0.bytes, self.remote.1)
     }
 
     fn matches(&self, ip: &Ipv4, tcp: &Tcp) -> bool {
@@ -102,881 +802,5753 @@ impl TcpHandle {
         && (self.remote.1 == 0 || tcp.header.src.get() == self.remote.1)
     }
 
-    fn create_tcp(&self, flags: u16, data: Vec<u8>) -> Tcp {
-        Tcp {
-            header: TcpHeader {
-                src: n16::new(self.local.1),
-                dst: n16::new(self.remote.1),
-                sequence: n32::new(self.seq),
-                ack_num: n32::new(self.ack),
-                flags: n16::new(((mem::size_of::<TcpHeader>() << 10) & 0xF000) as u16 | (flags & 0xFFF)),
-                window_size: n16::new(8192),
-                checksum: Checksum { data: 0 },
-                urgent_pointer: n16::new(0),
-            },
-            options: Vec::new(),
-            data: data
+    /// Check that a segment overlaps the receive window (RFC 793 section 3.3)
+    fn acceptable(&self, tcp: &Tcp) -> bool {
+        let seq = tcp.header.sequence.get();
+        let mut len = tcp.data.len() as u32;
+        if tcp.header.flags.get() & TCP_SYN == TCP_SYN {
+            len += 1;
+        }
+        if tcp.header.flags.get() & TCP_FIN == TCP_FIN {
+            len += 1;
         }
-    }
 
-    fn create_ip(&self, id: u16, data: Vec<u8>) -> Ipv4 {
-        Ipv4 {
-            header: Ipv4Header {
-                ver_hlen: 0x45,
-                services: 0,
-                len: n16::new((data.len() + mem::size_of::<Ipv4Header>()) as u16),
-                id: n16::new(id),
-                flags_fragment: n16::new(0),
-                ttl: self.ttl,
-                proto: 0x06,
-                checksum: Checksum { data: 0 },
-                src: self.local.0,
-                dst: self.remote.0
-            },
-            options: Vec::new(),
-            data: data
+        let window = self.window();
+        if window == 0 {
+            len == 0 && seq == self.rcv_nxt
+        } else if len == 0 {
+            seq_in_window(seq, self.rcv_nxt, window)
+        } else {
+            seq_in_window(seq, self.rcv_nxt, window) || seq_in_window(seq.wrapping_add(len - 1), self.rcv_nxt, window)
         }
     }
-}
-
-#[derive(Copy, Clone, Debug)]
-enum SettingKind {
-    Ttl,
-    ReadTimeout,
-    WriteTimeout
-}
 
-#[derive(Debug)]
-enum Handle {
-    Empty(EmptyHandle),
-    Tcp(TcpHandle),
-    Setting(usize, SettingKind),
-}
+    /// Remove the segments fully covered by a cumulative ACK, returning true if any were removed
+    fn acknowledge(&mut self, ack_num: u32, tsecr: Option<u32>, time: &TimeSpec) -> bool {
+        // The ACK must not be for data we have not sent yet
+        if seq_gt(ack_num, self.snd_nxt) {
+            return false;
+        }
 
-struct Tcpd {
-    scheme_file: File,
-    tcp_file: File,
-    time_file: File,
-    ports: BTreeMap<u16, usize>,
-    next_id: usize,
-    handles: BTreeMap<usize, Handle>,
-    rng: OsRng,
-}
+        let mut acked = 0;
+        if seq_gt(ack_num, self.snd_una) {
+            acked = ack_num.wrapping_sub(self.snd_una);
+            self.snd_una = ack_num;
+        }
 
-impl Tcpd {
-    fn new(scheme_file: File, tcp_file: File, time_file: File) -> Self {
-        Tcpd {
-            scheme_file: scheme_file,
-            tcp_file: tcp_file,
-            time_file: time_file,
-            ports: BTreeMap::new(),
-            next_id: 1,
-            handles: BTreeMap::new(),
-            rng: OsRng::new().expect("tcpd: failed to open RNG")
+        if self.snd_up.is_some_and(|snd_up| seq_leq(snd_up, self.snd_una)) {
+            self.snd_up = None;
         }
-    }
 
-    fn scheme_event(&mut self) -> io::Result<()> {
-        loop {
-            let mut packet = Packet::default();
-            if self.scheme_file.read(&mut packet)? == 0 {
+        let mut progress = false;
+        let mut rtt = None;
+        let mut ambiguous = false;
+        while let Some(end) = self.unacked.front().map(|segment| segment.end()) {
+            if seq_lt(ack_num, end) {
                 break;
             }
+            let segment = self.unacked.pop_front().unwrap();
+            progress = true;
 
-            let a = packet.a;
-            self.handle(&mut packet);
-            if packet.a == (-EWOULDBLOCK) as usize {
-                if let Some(mut handle) = self.handles.get_mut(&packet.b) {
-                    if let Handle::Tcp(ref mut handle) = *handle {
-                        match a {
-                            syscall::number::SYS_DUP => {
-                                packet.a = a;
-                                handle.todo_dup.push_back(packet);
-                            },
-                            syscall::number::SYS_READ => {
-                                packet.a = a;
-
-                                let timeout = match handle.read_timeout {
-                                    Some(read_timeout) => {
-                                        let mut time = TimeSpec::default();
-                                        syscall::clock_gettime(CLOCK_MONOTONIC, &mut time).map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+            // Karn's algorithm: an ACK covering a retransmission is ambiguous
+            ambiguous |= segment.retransmitted;
+            rtt = Some(to_micros(time).saturating_sub(to_micros(&segment.sent)));
+        }
 
-                                        let timeout = add_time(&time, &read_timeout);
-                                        self.time_file.write(&timeout)?;
-                                        Some(timeout)
-                                    },
-                                    None => None
-                                };
+        if let Some(rtt) = rtt {
+            // An echoed timestamp dates the segment that was acknowledged, even a retransmission
+            if let Some(tsecr) = tsecr {
+                self.update_rtt(timestamp(time).wrapping_sub(tsecr) as u64 * 1000);
+            } else if ! ambiguous {
+                self.update_rtt(rtt);
+            }
+        }
 
-                                handle.todo_read.push_back((timeout, packet));
-                            },
-                            syscall::number::SYS_WRITE => {
-                                packet.a = a;
+        if progress {
+            self.rto = self.computed_rto();
+            self.retries = 0;
+            self.grow_cwnd(acked);
+        }
 
-                                let timeout = match handle.write_timeout {
-                                    Some(write_timeout) => {
-                                        let mut time = TimeSpec::default();
-                                        syscall::clock_gettime(CLOCK_MONOTONIC, &mut time).map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+        progress
+    }
 
-                                        let timeout = add_time(&time, &write_timeout);
-                                        self.time_file.write(&timeout)?;
-                                        Some(timeout)
-                                    },
-                                    None => None
-                                };
+    /// Mark the unacknowledged segments covered by the peer's SACK blocks (RFC 2018)
+    fn mark_sacked(&mut self, blocks: &[(u32, u32)]) {
+        for segment in self.unacked.iter_mut() {
+            let (start, end) = (segment.seq, segment.end());
+            if blocks.iter().any(|&(left, right)| seq_leq(left, start) && seq_leq(end, right)) {
+                segment.sacked = true;
+            }
+        }
+    }
 
-                                handle.todo_write.push_back((timeout, packet));
-                            },
-                            _ => {
-                                self.scheme_file.write(&packet)?;
-                            }
-                        }
-                    }
-                }
-            } else {
-                self.scheme_file.write(&packet)?;
+    /// Send again each segment with at least `DUP_THRESH` SACKed segments after it, which have
+    /// overtaken it so it is presumed lost (RFC 6675 section 4). Only these holes are resent,
+    /// never what the peer already has
+    fn retransmit_lost(&mut self, time: TimeSpec, tcp_file: &mut File, rng: &mut OsRng) -> io::Result<bool> {
+        let mut lost = Vec::new();
+        let mut sacked_after = 0;
+        for (i, segment) in self.unacked.iter().enumerate().rev() {
+            if segment.sacked {
+                sacked_after += 1;
+            } else if sacked_after >= DUP_THRESH && ! segment.retransmitted {
+                lost.push(i);
             }
         }
 
-        Ok(())
-    }
+        if lost.is_empty() {
+            return Ok(false);
+        }
 
-    fn tcp_event(&mut self) -> io::Result<()> {
-        loop {
-            let mut bytes = [0; 65536];
-            let count = self.tcp_file.read(&mut bytes)?;
-            if count == 0 {
-                break;
-            }
-            if let Some(ip) = Ipv4::from_bytes(&bytes[.. count]) {
-                if let Some(tcp) = Tcp::from_bytes(&ip.data) {
-                    let mut closing = Vec::new();
-                    let mut found_connection = false;
-                    for (id, handle) in self.handles.iter_mut() {
-                        if let Handle::Tcp(ref mut handle) = *handle {
-                            if handle.state != State::Listen && handle.matches(&ip, &tcp) {
-                                found_connection = true;
-
-                                match handle.state {
-                                    State::SynReceived => if tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_ACK && tcp.header.ack_num.get() == handle.seq {
-                                        handle.state = State::Established;
-                                    },
-                                    State::SynSent => if tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK && tcp.header.ack_num.get() == handle.seq {
-                                        handle.state = State::Established;
-                                        handle.ack = tcp.header.sequence.get() + 1;
-
-                                        let tcp = handle.create_tcp(TCP_ACK, Vec::new());
-                                        let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                                        self.tcp_file.write(&ip.to_bytes())?;
-                                    },
-                                    State::Established => if tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_ACK && tcp.header.ack_num.get() == handle.seq {
-                                        handle.ack = tcp.header.sequence.get();
+        // Every loss within one window is a single sign of congestion
+        if self.recover.is_none_or(|recover| seq_leq(recover, self.snd_una)) {
+            let mss = self.remote_mss as u32;
+            self.ssthresh = cmp::max(self.cwnd / 2, 2 * mss);
+            self.cwnd = self.ssthresh;
+            self.recover = Some(self.snd_nxt);
+        }
 
-                                        if ! tcp.data.is_empty() {
-                                            handle.data.push_back((ip.clone(), tcp.clone()));
-                                            handle.ack += tcp.data.len() as u32;
+        for i in lost.into_iter().rev() {
+            let (seq, flags, data) = (self.unacked[i].seq, self.unacked[i].flags, self.unacked[i].data.clone());
+            let mut tcp = self.create_tcp(flags, data);
+            tcp.header.sequence.set(seq);
+            self.set_urgent(&mut tcp);
+            let ip = self.create_ip(rng.gen(), tcp.to_bytes());
+            tcp_file.write_all(&ip.to_bytes())?;
 
-                                            let tcp = handle.create_tcp(TCP_ACK, Vec::new());
-                                            let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                                            self.tcp_file.write(&ip.to_bytes())?;
-                                        } else if tcp.header.flags.get() & TCP_FIN == TCP_FIN {
-                                            handle.state = State::CloseWait;
+            self.unacked[i].sent = time;
+            self.unacked[i].retransmitted = true;
+            self.retransmits += 1;
+        }
 
-                                            handle.ack += 1;
+        Ok(true)
+    }
 
-                                            let tcp = handle.create_tcp(TCP_ACK, Vec::new());
-                                            let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                                            self.tcp_file.write(&ip.to_bytes())?;
-                                        }
-                                    },
-                                    //TODO: Time wait
-                                    State::FinWait1 => if tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_ACK && tcp.header.ack_num.get() == handle.seq {
-                                        handle.ack = tcp.header.sequence.get() + 1;
+    /// Open the congestion window for newly acknowledged data, by up to one MSS per ACK
+    /// during slow start and by about one MSS per round trip during congestion avoidance
+    fn grow_cwnd(&mut self, acked: u32) {
+        let mss = self.remote_mss as u32;
+        if self.cwnd < self.ssthresh {
+            self.cwnd = self.cwnd.saturating_add(cmp::min(acked, mss));
+        } else {
+            self.cwnd = self.cwnd.saturating_add(cmp::max(mss * mss / cmp::max(self.cwnd, 1), 1));
+        }
+    }
 
-                                        if tcp.header.flags.get() & TCP_FIN == TCP_FIN {
-                                            handle.state = State::TimeWait;
+    /// Lower the MSS to fit a smaller path MTU, splitting the segments in flight that no longer fit
+    fn reduce_mss(&mut self, mss: u16) {
+        if mss >= self.remote_mss {
+            return;
+        }
+        self.remote_mss = mss;
+        self.cwnd = cmp::min(self.cwnd, INITIAL_CWND * mss as u32);
 
-                                            let tcp = handle.create_tcp(TCP_ACK, Vec::new());
-                                            let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                                            self.tcp_file.write(&ip.to_bytes())?;
+        let mut unacked = VecDeque::new();
+        for segment in self.unacked.drain(..) {
+            if segment.data.len() <= mss as usize {
+                unacked.push_back(segment);
+                continue;
+            }
 
-                                            closing.push(*id);
-                                        } else {
-                                            handle.state = State::FinWait2;
-                                        }
-                                    },
-                                    State::FinWait2 => if tcp.header.flags.get() & (TCP_SYN | TCP_ACK | TCP_FIN) == TCP_ACK | TCP_FIN && tcp.header.ack_num.get() == handle.seq {
-                                        handle.ack = tcp.header.sequence.get() + 1;
+            let mut seq = segment.seq;
+            for chunk in segment.data.chunks(mss as usize) {
+                unacked.push_back(Segment {
+                    seq,
+                    flags: segment.flags,
+                    data: chunk.to_vec(),
+                    sent: segment.sent,
+                    retransmitted: segment.retransmitted,
+                    sacked: segment.sacked
+                });
+                seq = seq.wrapping_add(chunk.len() as u32);
+            }
+        }
+        self.unacked = unacked;
+    }
 
-                                        handle.state = State::TimeWait;
+    /// Set the congestion window for a newly negotiated MSS
+    fn reset_cwnd(&mut self) {
+        self.cwnd = INITIAL_CWND * self.remote_mss as u32;
+        self.ssthresh = u32::MAX;
+    }
 
-                                        let tcp = handle.create_tcp(TCP_ACK, Vec::new());
-                                        let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                                        self.tcp_file.write(&ip.to_bytes())?;
+    /// Feed a round trip time sample, in microseconds, into the estimators
+    fn update_rtt(&mut self, rtt: u64) {
+        match self.srtt {
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (3 * self.rttvar + delta) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            },
+            None => {
+                self.rttvar = rtt / 2;
+                self.srtt = Some(rtt);
+            }
+        }
+    }
 
-                                        closing.push(*id);
-                                    },
-                                    State::LastAck => if tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_ACK && tcp.header.ack_num.get() == handle.seq {
-                                        handle.state = State::Closed;
-                                        closing.push(*id);
-                                    },
-                                    _ => ()
-                                }
+    /// Retransmission timeout derived from the round trip time estimators, in milliseconds
+    fn computed_rto(&self) -> u64 {
+        match self.srtt {
+            Some(srtt) => ((srtt + 4 * self.rttvar) / 1000).clamp(RTO_MIN, self.rto_max),
+            None => RTO_INITIAL
+        }
+    }
 
-                                while ! handle.todo_read.is_empty() && (! handle.data.is_empty() || handle.read_closed()) {
-                                    let (_timeout, mut packet) = handle.todo_read.pop_front().unwrap();
-                                    let buf = unsafe { slice::from_raw_parts_mut(packet.c as *mut u8, packet.d) };
-                                    if let Some((ip, mut tcp)) = handle.data.pop_front() {
-                                        let len = std::cmp::min(buf.len(), tcp.data.len());
-                                        for (i, c) in tcp.data.drain(0..len).enumerate() {
-                                            buf[i] = c;
-                                        }
-                                        if !tcp.data.is_empty() {
-                                            handle.data.push_front((ip, tcp));
-                                        }
-                                        packet.a = len;
-                                    } else {
-                                        packet.a = 0;
-                                    }
+    /// Record a sent segment for retransmission
+    fn push_unacked(&mut self, flags: u16, data: Vec<u8>, time: TimeSpec) {
+        self.unacked.push_back(Segment {
+            seq: self.snd_nxt,
+            flags,
+            data,
+            sent: time,
+            retransmitted: false,
+            sacked: false
+        });
+    }
 
-                                    self.scheme_file.write(&packet)?;
-                                }
+    /// Move snd_nxt past `len` sent sequence numbers. Sequence numbers wrap at 2^32, so they
+    /// only ever advance through here or `advance_rcv`
+    fn advance_snd(&mut self, len: u32) {
+        self.snd_nxt = self.snd_nxt.wrapping_add(len);
+    }
 
-                                if ! handle.todo_write.is_empty() && handle.state == State::Established {
-                                    let (_timeout, mut packet) = handle.todo_write.pop_front().unwrap();
-                                    let buf = unsafe { slice::from_raw_parts(packet.c as *const u8, packet.d) };
+    /// Move rcv_nxt past `len` received sequence numbers
+    fn advance_rcv(&mut self, len: u32) {
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(len);
+    }
 
-                                    let tcp = handle.create_tcp(TCP_ACK | TCP_PSH, buf.to_vec());
-                                    let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                                    let result = self.tcp_file.write(&ip.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)));
-                                    if result.is_ok() {
-                                        handle.seq += buf.len() as u32;
-                                    }
-                                    packet.a = Error::mux(result.and(Ok(buf.len())));
+    /// When to probe the peer's window, if it is closed or too small for the data waiting behind
+    /// it. The interval starts at the retransmission timeout and doubles with every probe
+    fn persist_deadline(&self, time: &TimeSpec) -> Option<TimeSpec> {
+        if self.unacked.is_empty() && ! self.send_buf.is_empty() {
+            let interval = cmp::min(self.rto << cmp::min(self.probes, 16), self.rto_max);
+            Some(add_time(time, &millis(interval)))
+        } else {
+            None
+        }
+    }
 
-                                    self.scheme_file.write(&packet)?;
-                                }
+    /// When the oldest unacknowledged segment is due for retransmission
+    fn retransmit_deadline(&self) -> Option<TimeSpec> {
+        self.unacked.front().map(|segment| add_time(&segment.sent, &millis(self.rto)))
+    }
 
-                                if handle.events & EVENT_READ == EVENT_READ {
-                                    if let Some(&(ref _ip, ref tcp)) = handle.data.get(0) {
-                                        self.scheme_file.write(&Packet {
-                                            id: 0,
-                                            pid: 0,
-                                            uid: 0,
-                                            gid: 0,
-                                            a: syscall::number::SYS_FEVENT,
-                                            b: *id,
-                                            c: EVENT_READ,
-                                            d: tcp.data.len()
-                                        })?;
-                                    }
-                                }
-                            }
-                        }
-                    }
+    /// Append as much data to the send buffer as fits, returning the amount taken
+    fn buffer_data(&mut self, data: &[u8]) -> usize {
+        let count = cmp::min(data.len(), self.send_buffer.saturating_sub(self.send_buf.len()));
+        self.send_buf.extend(&data[.. count]);
+        count
+    }
 
-                    for file in closing {
-                        if let Handle::Tcp(handle) = self.handles.remove(&file).unwrap() {
-                            let remove = if let Some(mut port) = self.ports.get_mut(&handle.local.1) {
-                                *port = *port + 1;
-                                *port == 0
-                            } else {
-                                false
-                            };
+    /// Take received data for the application, returning the amount copied. A read normally
+    /// stops at the end of a segment, or with `push_boundaries` at the end of the next
+    /// segment that had PSH set
+    fn read_data(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        while let Some((ip, mut tcp)) = self.data.pop_front() {
+            let count = cmp::min(buf.len() - len, tcp.data.len());
+            for (i, c) in tcp.data.drain(0..count).enumerate() {
+                buf[len + i] = c;
+            }
+            len += count;
 
-                            if remove {
-                                self.ports.remove(&handle.local.1);
-                            }
-                        }
-                    }
+            if ! tcp.data.is_empty() {
+                self.data.push_front((ip, tcp));
+                break;
+            } else if ! self.push_boundaries || tcp.header.flags.get() & TCP_PSH == TCP_PSH {
+                break;
+            }
+        }
+        len
+    }
 
-                    if ! found_connection && tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_SYN {
-                        let mut new_handles = Vec::new();
-
-                        for (id, handle) in self.handles.iter_mut() {
-                            if let Handle::Tcp(ref mut handle) = *handle {
-                                if handle.state == State::Listen && handle.matches(&ip, &tcp) {
-                                    handle.data.push_back((ip.clone(), tcp.clone()));
-
-                                    while ! handle.todo_dup.is_empty() && ! handle.data.is_empty() {
-                                        let mut packet = handle.todo_dup.pop_front().unwrap();
-                                        let (ip, tcp) = handle.data.pop_front().unwrap();
-
-                                        let mut new_handle = TcpHandle {
-                                            local: handle.local,
-                                            remote: (ip.header.src, tcp.header.src.get()),
-                                            flags: handle.flags,
-                                            events: 0,
-                                            read_timeout: handle.read_timeout,
-                                            write_timeout: handle.write_timeout,
-                                            ttl: handle.ttl,
-                                            state: State::SynReceived,
-                                            seq: self.rng.gen(),
-                                            ack: tcp.header.sequence.get() + 1,
-                                            data: VecDeque::new(),
-                                            todo_dup: VecDeque::new(),
-                                            todo_read: VecDeque::new(),
-                                            todo_write: VecDeque::new(),
-                                        };
+    /// Send buffered data in segments no larger than the peer's MSS, queueing each for
+    /// retransmission. Data stays buffered while the peer's window or the congestion window
+    /// is full, and a segment smaller than the MSS waits for outstanding data to be
+    /// acknowledged unless `nodelay` is set (Nagle's algorithm). With `force` everything is
+    /// sent regardless.
+    fn output(&mut self, force: bool, time: TimeSpec, tcp_file: &mut File, rng: &mut OsRng) -> io::Result<()> {
+        let mss = self.segment_size();
 
-                                        let tcp = new_handle.create_tcp(TCP_SYN | TCP_ACK, Vec::new());
-                                        let ip = new_handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                                        self.tcp_file.write(&ip.to_bytes())?;
+        while ! self.send_buf.is_empty() {
+            let mut len = cmp::min(self.send_buf.len(), mss);
 
-                                        new_handle.seq += 1;
+            if ! force {
+                // A closed window is left to the persist timer to probe
+                len = cmp::min(len, self.usable_window());
+                if len == 0 {
+                    break;
+                }
 
-                                        handle.data.retain(|&(ref ip, ref tcp)| {
-                                            if new_handle.matches(ip, tcp) {
-                                                false
-                                            } else {
-                                                true
-                                            }
-                                        });
+                // Nagle's algorithm holds back the last small piece while anything is in flight
+                if len == self.send_buf.len() && len < mss && ! self.nodelay && ! self.unacked.is_empty() {
+                    break;
+                }
 
-                                        if let Some(mut port) = self.ports.get_mut(&handle.local.1) {
-                                            *port = *port + 1;
-                                        }
+                // A segment cut short by the window waits for it to open, even with nodelay, unless
+                // it is at least half the largest window offered (RFC 1122 section 4.2.3.4). If
+                // nothing else comes the persist timer sends it anyway
+                if len < self.send_buf.len() && len < mss && (len as u32) < self.max_snd_wnd / 2 {
+                    break;
+                }
+            }
 
-                                        let id = self.next_id;
-                                        self.next_id += 1;
+            self.send_segment(len, time, tcp_file, rng)?;
+        }
 
-                                        packet.a = id;
+        Ok(())
+    }
 
-                                        new_handles.push((packet, Handle::Tcp(new_handle)));
-                                    }
+    /// Send the next `len` bytes of the send buffer in one segment
+    fn send_segment(&mut self, len: usize, time: TimeSpec, tcp_file: &mut File, rng: &mut OsRng) -> io::Result<()> {
+        let data: Vec<u8> = self.send_buf.drain(.. len).collect();
+        let flags = if self.send_buf.is_empty() {
+            TCP_ACK | TCP_PSH
+        } else {
+            TCP_ACK
+        };
 
-                                    if handle.events & EVENT_READ == EVENT_READ {
-                                        if let Some(&(ref _ip, ref tcp)) = handle.data.get(0) {
-                                            self.scheme_file.write(&Packet {
-                                                id: 0,
-                                                pid: 0,
-                                                uid: 0,
-                                                gid: 0,
-                                                a: syscall::number::SYS_FEVENT,
-                                                b: *id,
-                                                c: EVENT_READ,
-                                                d: tcp.data.len()
-                                            })?;
-                                        }
-                                    }
-                                }
-                            }
-                        }
+        // The first new data after a reduction tells the peer about it. Only new data is marked
+        // ECN capable, never retransmissions or bare ACKs (RFC 3168 section 6.1.4)
+        let cwr = if self.cwr {
+            self.cwr = false;
+            TCP_CWR
+        } else {
+            0
+        };
 
-                        for (packet, new_handle) in new_handles {
-                            self.handles.insert(packet.a, new_handle);
-                            self.scheme_file.write(&packet)?;
-                        }
-                    }
-                }
-            }
+        let tcp = self.create_tcp(flags | cwr, data.clone());
+        let mut ip = self.create_ip(rng.gen(), tcp.to_bytes());
+        if self.ecn_ok {
+            ip.header.services |= IP_ECT0;
         }
+        tcp_file.write_all(&ip.to_bytes())?;
+
+        self.push_unacked(flags, data, time);
+        self.advance_snd(len as u32);
+        self.bytes_sent += len as u64;
+        self.ack_pending = false;
 
         Ok(())
     }
 
-    fn time_event(&mut self) -> io::Result<()> {
-        let mut time = TimeSpec::default();
-        if self.time_file.read(&mut time)? < mem::size_of::<TimeSpec>() {
-            return Err(io::Error::from_raw_os_error(EINVAL));
-        }
+    /// Queue data that arrived past a gap, merging it with the blocks it overlaps or touches
+    fn queue_reassembly(&mut self, seq: u32, data: Vec<u8>) {
+        let mut start = seq;
+        let mut data = data;
 
-        for (_id, handle) in self.handles.iter_mut() {
-            if let Handle::Tcp(ref mut handle) = *handle {
-                let mut i = 0;
-                while i < handle.todo_read.len() {
-                    if let Some(timeout) =  handle.todo_read.get(i).map(|e| e.0.clone()).unwrap_or(None) {
-                        if time.tv_sec > timeout.tv_sec || (time.tv_sec == timeout.tv_sec && time.tv_nsec >= timeout.tv_nsec) {
-                            let (_timeout, mut packet) = handle.todo_read.remove(i).unwrap();
-                            packet.a = (-ETIMEDOUT) as usize;
-                            self.scheme_file.write(&packet)?;
-                        } else {
-                            i += 1;
-                        }
-                    } else {
-                        i += 1;
-                    }
-                }
+        let mut i = 0;
+        while i < self.reassembly.len() {
+            let block_start = self.reassembly[i].0;
+            let block_end = block_start.wrapping_add(self.reassembly[i].1.len() as u32);
+            if seq_lt(block_end, start) || seq_gt(block_start, start.wrapping_add(data.len() as u32)) {
+                i += 1;
+                continue;
+            }
 
-                let mut i = 0;
-                while i < handle.todo_write.len() {
-                    if let Some(timeout) = handle.todo_write.get(i).map(|e| e.0.clone()).unwrap_or(None) {
-                        if time.tv_sec > timeout.tv_sec || (time.tv_sec == timeout.tv_sec && time.tv_nsec >= timeout.tv_nsec) {
-                            let (_timeout, mut packet) = handle.todo_write.remove(i).unwrap();
-                            packet.a = (-ETIMEDOUT) as usize;
-                            self.scheme_file.write(&packet)?;
-                        } else {
-                            i += 1;
-                        }
-                    } else {
-                        i += 1;
-                    }
-                }
+            let (_, block) = self.reassembly.remove(i).unwrap();
+            if seq_lt(block_start, start) {
+                let mut merged = block[.. start.wrapping_sub(block_start) as usize].to_vec();
+                merged.extend(data);
+                data = merged;
+                start = block_start;
+            }
+
+            let end = start.wrapping_add(data.len() as u32);
+            if seq_gt(block_end, end) {
+                data.extend_from_slice(&block[end.wrapping_sub(block_start) as usize ..]);
             }
         }
 
-        Ok(())
+        self.reassembly.push_front((start, data));
     }
 
-    fn inner_dup(&mut self, file: usize, path: &str) -> Result<Handle> {
-        Ok(match *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
-            Handle::Empty(ref handle) => {
-                if path.is_empty() {
-                    Handle::Empty(EmptyHandle {
-                        privileged: handle.privileged,
-                        flags: handle.flags
-                    })
-                } else {
-                    let mut parts = path.split("/");
-                    let remote = parse_socket(parts.next().unwrap_or(""));
-                    let mut local = parse_socket(parts.next().unwrap_or(""));
+    /// Append the queued data that continues from sequence number `next`
+    fn take_reassembled(&mut self, next: u32, data: &mut Vec<u8>) {
+        let mut next = next;
+        while let Some(i) = self.reassembly.iter().position(|&(start, _)| seq_leq(start, next)) {
+            let (start, block) = self.reassembly.remove(i).unwrap();
+            let offset = next.wrapping_sub(start) as usize;
+            if offset < block.len() {
+                data.extend_from_slice(&block[offset ..]);
+                next = start.wrapping_add(block.len() as u32);
+            }
+        }
+    }
 
-                    if local.1 == 0 {
-                        local.1 = self.rng.gen_range(32768, 65535);
-                    }
+    /// Send everything still buffered followed by a FIN, queueing the FIN for retransmission
+    fn send_fin(&mut self, time: TimeSpec, tcp_file: &mut File, rng: &mut OsRng) -> io::Result<()> {
+        self.output(true, time, tcp_file, rng)?;
 
-                    if local.1 <= 1024 && ! handle.privileged {
-                        return Err(Error::new(EACCES));
-                    }
+        let tcp = self.create_tcp(TCP_FIN | TCP_ACK, Vec::new());
+        let ip = self.create_ip(rng.gen(), tcp.to_bytes());
+        tcp_file.write_all(&ip.to_bytes())?;
 
-                    if self.ports.contains_key(&local.1) {
-                        return Err(Error::new(EADDRINUSE));
-                    }
+        self.push_unacked(TCP_FIN | TCP_ACK, Vec::new(), time);
+        self.advance_snd(1);
 
-                    let mut new_handle = TcpHandle {
-                        local: local,
-                        remote: remote,
-                        flags: handle.flags,
-                        events: 0,
-                        read_timeout: None,
-                        write_timeout: None,
-                        ttl: 64,
-                        state: State::Listen,
-                        seq: 0,
-                        ack: 0,
-                        data: VecDeque::new(),
-                        todo_dup: VecDeque::new(),
-                        todo_read: VecDeque::new(),
-                        todo_write: VecDeque::new(),
-                    };
+        Ok(())
+    }
 
-                    if new_handle.is_connected() {
-                        new_handle.seq = self.rng.gen();
-                        new_handle.ack = 0;
-                        new_handle.state = State::SynSent;
+    /// Whether everything written has been sent and acknowledged
+    fn synced(&self) -> bool {
+        self.send_buf.is_empty() && self.snd_una == self.snd_nxt
+    }
 
-                        let tcp = new_handle.create_tcp(TCP_SYN, Vec::new());
-                        let ip = new_handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                        self.tcp_file.write(&ip.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+    /// Answer every blocked read and write with an error
+    fn fail_todo(&mut self, scheme_file: &mut File, err: i32) -> io::Result<()> {
+        while let Some((_timeout, mut packet)) = self.todo_read.pop_front() {
+            packet.a = (-err) as usize;
+            scheme_file.write_all(&packet)?;
+        }
 
-                        new_handle.seq += 1;
-                    }
+        while let Some((_timeout, mut packet)) = self.todo_write.pop_front() {
+            packet.a = (-err) as usize;
+            scheme_file.write_all(&packet)?;
+        }
 
-                    self.ports.insert(new_handle.local.1, 1);
+        while let Some(mut packet) = self.todo_close.pop_front() {
+            packet.a = (-err) as usize;
+            scheme_file.write_all(&packet)?;
+        }
 
-                    Handle::Tcp(new_handle)
-                }
-            },
-            Handle::Tcp(ref mut handle) => {
-                let mut new_handle = TcpHandle {
-                    local: handle.local,
-                    remote: handle.remote,
-                    flags: handle.flags,
-                    events: 0,
-                    read_timeout: handle.read_timeout,
-                    write_timeout: handle.write_timeout,
-                    ttl: handle.ttl,
-                    state: handle.state,
-                    seq: handle.seq,
-                    ack: handle.ack,
-                    data: VecDeque::new(),
-                    todo_dup: VecDeque::new(),
-                    todo_read: VecDeque::new(),
-                    todo_write: VecDeque::new(),
-                };
+        while let Some(mut packet) = self.todo_sync.pop_front() {
+            packet.a = (-err) as usize;
+            scheme_file.write_all(&packet)?;
+        }
 
-                if path == "ttl" {
-                    Handle::Setting(file, SettingKind::Ttl)
-                } else if path == "read_timeout" {
-                    Handle::Setting(file, SettingKind::ReadTimeout)
-                } else if path == "write_timeout" {
-                    Handle::Setting(file, SettingKind::WriteTimeout)
-                } else if path == "listen" {
-                    if handle.is_connected() {
-                        return Err(Error::new(EISCONN));
-                    } else if let Some((ip, tcp)) = handle.data.pop_front() {
-                        new_handle.remote = (ip.header.src, tcp.header.src.get());
+        Ok(())
+    }
 
-                        new_handle.seq = self.rng.gen();
-                        new_handle.ack = tcp.header.sequence.get() + 1;
-                        new_handle.state = State::SynReceived;
+    /// Fail a connect in progress or a connection that was reset, and wake anything polling the
+    /// handle. With nothing waiting the error is kept for the next read or write
+    fn fail_connection(&mut self, id: usize, err: i32, scheme_file: &mut File) -> io::Result<()> {
+        self.state = State::Closed;
+        self.unacked.clear();
 
-                        let tcp = new_handle.create_tcp(TCP_SYN | TCP_ACK, Vec::new());
-                        let ip = new_handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                        self.tcp_file.write(&ip.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+        if self.todo_read.is_empty() && self.todo_write.is_empty() && self.todo_close.is_empty() && self.todo_sync.is_empty() {
+            self.error = Some(err);
+        } else {
+            self.fail_todo(scheme_file, err)?;
+        }
 
-                        new_handle.seq += 1;
-                    } else {
-                        return Err(Error::new(EWOULDBLOCK));
-                    }
+        if self.events & (EVENT_READ | EVENT_WRITE) != 0 {
+            scheme_file.write_all(&Packet {
+                id: 0,
+                pid: 0,
+                uid: 0,
+                gid: 0,
+                a: syscall::number::SYS_FEVENT,
+                b: id,
+                c: self.events & (EVENT_READ | EVENT_WRITE),
+                d: 0
+            })?;
+        }
 
-                    handle.data.retain(|&(ref ip, ref tcp)| {
-                        if new_handle.matches(ip, tcp) {
-                            false
-                        } else {
-                            true
-                        }
-                    });
+        Ok(())
+    }
 
-                    Handle::Tcp(new_handle)
-                } else if path.is_empty() {
-                    new_handle.data = handle.data.clone();
+    /// Give up on a peer that has stopped answering. It is reset in case it comes back, and the
+    /// connection fails with ETIMEDOUT. An application that still holds the handle learns why on
+    /// its next call
+    fn give_up(&mut self, id: usize, tcp_file: &mut File, rng: &mut OsRng, scheme_file: &mut File) -> io::Result<()> {
+        let tcp = self.create_tcp(TCP_RST | TCP_ACK, Vec::new());
+        let ip = self.create_ip(rng.gen(), tcp.to_bytes());
+        tcp_file.write_all(&ip.to_bytes())?;
 
-                    Handle::Tcp(new_handle)
-                } else {
-                    return Err(Error::new(EINVAL));
-                }
-            },
-            Handle::Setting(file, kind) => {
-                Handle::Setting(file, kind)
-            }
-        })
+        self.fail_connection(id, ETIMEDOUT, scheme_file)
     }
-}
 
-impl SchemeMut for Tcpd {
-    fn open(&mut self, url: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
-        let path = str::from_utf8(url).or(Err(Error::new(EINVAL)))?;
+    /// Bytes received but not yet read by the application
+    fn buffered(&self) -> usize {
+        self.data.iter().map(|(_, tcp)| tcp.data.len()).sum()
+    }
 
-        let id = self.next_id;
-        self.next_id += 1;
+    /// Largest window that can be advertised with the agreed scale
+    fn max_window(&self) -> u32 {
+        let shift = self.rcv_wscale.unwrap_or(0);
+        cmp::min(RECEIVE_BUFFER, (u16::MAX as usize) << shift) as u32
+    }
 
-        self.handles.insert(id, Handle::Empty(EmptyHandle {
-            privileged: uid == 0,
-            flags: flags
-        }));
+    /// Receive window offered to the peer, in bytes
+    fn window(&self) -> u32 {
+        cmp::min(self.rcv_wnd, self.max_window())
+    }
 
-        match self.inner_dup(id, path) {
-            Ok(handle) => {
-                self.handles.insert(id, handle);
-                Ok(id)
-            },
-            Err(err) => {
-                self.handles.remove(&id);
-                Err(err)
-            }
+    /// Open the window for buffer space freed by the application. To avoid silly window
+    /// syndrome it only opens once at least an MSS or half the buffer has been freed
+    fn update_window(&mut self) {
+        let free = cmp::min(RECEIVE_BUFFER.saturating_sub(self.buffered()) as u32, self.max_window());
+        if free > self.rcv_wnd && free - self.rcv_wnd >= cmp::min(local_mss() as u32, RECEIVE_BUFFER as u32 / 2) {
+            self.rcv_wnd = free;
         }
     }
 
-    fn dup(&mut self, file: usize, buf: &[u8]) -> Result<usize> {
-        let path = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
+    /// Window field for an outgoing segment, which is never scaled on a SYN
+    fn window_field(&self, flags: u16) -> u16 {
+        if flags & TCP_SYN == TCP_SYN {
+            cmp::min(self.window(), u16::MAX as u32) as u16
+        } else {
+            (self.window() >> self.rcv_wscale.unwrap_or(0)) as u16
+        }
+    }
 
-        let handle = self.inner_dup(file, path)?;
+    /// Take the peer's window from a segment, unless it is older than the one the window was
+    /// last taken from and so may shrink it again (RFC 793 section 3.9, SND.WL1 and SND.WL2)
+    fn update_snd_wnd(&mut self, tcp: &Tcp) {
+        let seq = tcp.header.sequence.get();
+        let ack = tcp.header.ack_num.get();
+        if tcp.header.flags.get() & TCP_SYN == TCP_SYN {
+            self.snd_wnd = tcp.header.window_size.get() as u32;
+        } else if seq_lt(self.snd_wl1, seq) || (self.snd_wl1 == seq && seq_leq(self.snd_wl2, ack)) {
+            self.snd_wnd = (tcp.header.window_size.get() as u32) << self.snd_wscale;
+        } else {
+            return;
+        }
+        self.snd_wl1 = seq;
+        self.snd_wl2 = ack;
+    }
 
-        let id = self.next_id;
-        self.next_id += 1;
+    /// Sequence numbers sent but not yet acknowledged
+    fn in_flight(&self) -> usize {
+        self.snd_nxt.wrapping_sub(self.snd_una) as usize
+    }
 
-        self.handles.insert(id, handle);
+    /// Most data a segment can carry. The peer's MSS does not allow for options, so they come out
+    /// of each segment
+    fn segment_size(&self) -> usize {
+        cmp::max((self.remote_mss as usize).saturating_sub(self.create_options(TCP_ACK).len()), 1)
+    }
 
-        Ok(id)
+    /// How much more the congestion and peer windows allow in flight
+    fn usable_window(&self) -> usize {
+        (cmp::min(self.cwnd, self.snd_wnd) as usize).saturating_sub(self.in_flight())
     }
 
-    fn read(&mut self, file: usize, buf: &mut [u8]) -> Result<usize> {
-        let (file, kind) = match *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
-            Handle::Empty(ref _handle) => {
-                return Err(Error::new(EBADF));
+    /// Options carried by a segment with these flags
+    fn create_options(&self, flags: u16) -> Vec<u8> {
+        let mut options = Vec::new();
+        if flags & TCP_SYN == TCP_SYN {
+            options.extend_from_slice(&mss_option(self.max_seg));
+            if let Some(shift) = self.rcv_wscale {
+                options.extend_from_slice(&wscale_option(shift));
+            }
+            // A SYN-ACK only offers SACK back to a peer that offered it
+            if flags & TCP_ACK != TCP_ACK || self.sack {
+                options.extend_from_slice(&sack_permitted_option());
+            }
+        } else if flags & TCP_ACK == TCP_ACK && self.sack && ! self.reassembly.is_empty() {
+            options.extend(sack_option(&self.reassembly));
+        }
+
+        // Offered on our SYN, then sent on every segment once both sides have
+        if self.timestamps || flags & (TCP_SYN | TCP_ACK) == TCP_SYN {
+            let tsval = monotonic().map(|time| timestamp(&time)).unwrap_or(0);
+            options.extend_from_slice(&timestamps_option(tsval, self.ts_recent));
+        }
+
+        options
+    }
+
+    fn create_tcp(&self, flags: u16, data: Vec<u8>) -> Tcp {
+        let mut flags = flags;
+        if flags & (TCP_SYN | TCP_ACK) == TCP_SYN {
+            if self.ecn {
+                flags |= TCP_ECE | TCP_CWR;
+            }
+        } else if flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK {
+            if self.ecn_ok {
+                flags |= TCP_ECE;
+            }
+        } else if self.ece && self.ecn_ok && flags & TCP_RST != TCP_RST {
+            flags |= TCP_ECE;
+        }
+
+        let mut options = self.create_options(flags);
+        // The data offset counts 32 bit words, so the options end on a word boundary
+        while ! options.len().is_multiple_of(4) {
+            options.push(0);
+        }
+
+        let mut tcp = Tcp {
+            header: TcpHeader {
+                src: n16::new(self.local.1),
+                dst: n16::new(self.remote.1),
+                sequence: n32::new(self.snd_nxt),
+                ack_num: n32::new(self.rcv_nxt),
+                flags: n16::new((((mem::size_of::<TcpHeader>() + options.len()) << 10) & 0xF000) as u16 | (flags & 0xFFF)),
+                window_size: n16::new(self.window_field(flags)),
+                checksum: Checksum { data: 0 },
+                urgent_pointer: n16::new(0),
             },
-            Handle::Tcp(ref mut handle) => {
-                if ! handle.is_connected() {
-                    return Err(Error::new(ENOTCONN));
-                } else if let Some((ip, mut tcp)) = handle.data.pop_front() {
-                    let len = std::cmp::min(buf.len(), tcp.data.len());
-                    for (i, c) in tcp.data.drain(0..len).enumerate() {
-                        buf[i] = c;
-                    }
-                    if !tcp.data.is_empty() {
-                        handle.data.push_front((ip, tcp));
+            options,
+            data: data
+        };
+        self.set_urgent(&mut tcp);
+        tcp
+    }
+
+    /// Mark a segment sent before the urgent pointer, which is relative to its sequence number
+    /// and so has to be set again if that changes
+    fn set_urgent(&self, tcp: &mut Tcp) {
+        if let Some(snd_up) = self.snd_up {
+            let seq = tcp.header.sequence.get();
+            if ! tcp.data.is_empty() && seq_gt(snd_up, seq) {
+                tcp.header.flags.set(tcp.header.flags.get() | TCP_URG);
+                tcp.header.urgent_pointer.set(cmp::min(snd_up.wrapping_sub(seq), u16::MAX as u32) as u16);
+            }
+        }
+    }
+
+    fn create_ip(&self, id: u16, data: Vec<u8>) -> Ipv4 {
+        Ipv4 {
+            header: Ipv4Header {
+                ver_hlen: 0x45,
+                services: 0,
+                len: n16::new((data.len() + mem::size_of::<Ipv4Header>()) as u16),
+                id: n16::new(id),
+                flags_fragment: n16::new(IP_DF),
+                ttl: self.ttl,
+                proto: 0x06,
+                checksum: Checksum { data: 0 },
+                src: self.local.0,
+                dst: self.remote.0
+            },
+            options: Vec::new(),
+            data: data
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum SettingKind {
+    Ttl,
+    ReadTimeout,
+    WriteTimeout,
+    SynRetries,
+    SynTimeout,
+    Retries,
+    RtoMax,
+    Rtt,
+    Rto,
+    NoDelay,
+    Ecn,
+    KeepAlive,
+    PushBoundaries,
+    ReuseAddr,
+    TimeWait,
+    FinWait2,
+    Linger,
+    SendBuffer,
+    Backlog,
+    Pending,
+    Stats,
+    State,
+    Error,
+    PathMtu,
+    MaxSeg,
+    Shutdown,
+    Peek,
+    Oob
+}
+
+#[derive(Debug)]
+enum Handle {
+    Empty(EmptyHandle),
+    Tcp(Box<TcpHandle>),
+    Setting(usize, SettingKind),
+    /// A snapshot of the connection list and how much of it has been read
+    Connections(Vec<u8>, usize),
+}
+
+/// Allows up to a number of events in each second of monotonic time
+struct RateLimit {
+    limit: u32,
+    count: u32,
+    second: i64
+}
+
+impl RateLimit {
+    fn new(limit: u32) -> Self {
+        RateLimit {
+            limit,
+            count: 0,
+            second: 0
+        }
+    }
+
+    /// Count an event, returning false if the limit for this second has been reached
+    fn allow(&mut self, time: &TimeSpec) -> bool {
+        if time.tv_sec != self.second {
+            self.second = time.tv_sec;
+            self.count = 0;
+        }
+
+        if self.count < self.limit {
+            self.count += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Stateless SYN-ACKs for when too many connections are half open (RFC 4987 section 3.6). The
+/// cookie sent as our initial sequence number holds the period it was made in in its top 5 bits,
+/// the index of the MSS in the next 3 and a keyed hash of the connection in the rest
+struct SynCookies {
+    /// The secret for the current period, then the one for the period before
+    secrets: [u64; 2],
+    period: u64,
+    /// When a cookie was last sent, so that ACKs are only checked while they may be answering one
+    sent: Option<u64>
+}
+
+impl SynCookies {
+    fn new(rng: &mut OsRng) -> Self {
+        SynCookies {
+            secrets: [rng.gen(), rng.gen()],
+            period: 0,
+            sent: None
+        }
+    }
+
+    /// Move to the secret for the current period, keeping the previous one if it was just in use
+    fn rotate(&mut self, time: &TimeSpec, rng: &mut OsRng) {
+        let period = to_millis(time) / COOKIE_PERIOD;
+        if period == self.period + 1 {
+            self.secrets[1] = self.secrets[0];
+            self.secrets[0] = rng.gen();
+        } else if period != self.period {
+            self.secrets = [rng.gen(), rng.gen()];
+        }
+        self.period = period;
+    }
+
+    fn hash(secret: u64, period: u64, ip: &Ipv4, tcp: &Tcp, isn: u32) -> u32 {
+        let mut hasher = DefaultHasher::new();
+        secret.hash(&mut hasher);
+        period.hash(&mut hasher);
+        ip.header.src.bytes.hash(&mut hasher);
+        ip.header.dst.bytes.hash(&mut hasher);
+        tcp.header.src.get().hash(&mut hasher);
+        tcp.header.dst.get().hash(&mut hasher);
+        isn.hash(&mut hasher);
+        hasher.finish() as u32 & 0xFFFFFF
+    }
+
+    /// The cookie answering a SYN, for the largest MSS it can encode that does not exceed `mss`
+    fn make(&mut self, ip: &Ipv4, tcp: &Tcp, mss: u16) -> u32 {
+        let index = COOKIE_MSS.iter().rposition(|&size| size <= mss).unwrap_or(0);
+        self.sent = Some(self.period);
+
+        let hash = SynCookies::hash(self.secrets[0], self.period, ip, tcp, tcp.header.sequence.get());
+        ((self.period as u32 & 0x1F) << 27) | ((index as u32) << 24) | hash
+    }
+
+    /// The MSS encoded in the cookie an ACK acknowledges, if it is one of ours that has not expired
+    fn check(&self, ip: &Ipv4, tcp: &Tcp) -> Option<u16> {
+        if self.sent.is_none_or(|sent| self.period > sent + 1) {
+            return None;
+        }
+
+        let cookie = tcp.header.ack_num.get().wrapping_sub(1);
+        let isn = tcp.header.sequence.get().wrapping_sub(1);
+
+        let (secret, period) = if cookie >> 27 == self.period as u32 & 0x1F {
+            (self.secrets[0], self.period)
+        } else if self.period > 0 && cookie >> 27 == (self.period - 1) as u32 & 0x1F {
+            (self.secrets[1], self.period - 1)
+        } else {
+            return None;
+        };
+
+        if cookie & 0xFFFFFF == SynCookies::hash(secret, period, ip, tcp, isn) {
+            Some(COOKIE_MSS[(cookie >> 24 & 0x7) as usize])
+        } else {
+            None
+        }
+    }
+}
+
+struct Tcpd {
+    scheme_file: File,
+    tcp_file: File,
+    time_file: File,
+    timers: Timers,
+    ports: BTreeMap<u16, usize>,
+    next_id: usize,
+    handles: BTreeMap<usize, Handle>,
+    /// Handle of each connection, by local and remote address
+    connections: BTreeMap<ConnectionKey, usize>,
+    /// Connections owed an ACK once the current tcp_event pass has read everything
+    ack_now: BTreeSet<usize>,
+    /// Connections written to during the current scheme_event pass, sent once it has read everything
+    flush_now: BTreeSet<usize>,
+    rng: OsRng,
+    /// Segments dropped because of a bad IP or TCP checksum
+    checksum_errors: u64,
+    /// Segments dropped because their data offset was impossible
+    offset_errors: u64,
+    /// Segments dropped because their flags contradicted each other
+    flag_errors: u64,
+    /// Resets dropped because they fell outside the receive window
+    window_resets: u64,
+    /// Half open connections over all handles, the sum of their `half_open`
+    half_open: usize,
+    /// TimeWait duration given to new connections, in milliseconds
+    time_wait: u64,
+    /// Resets sent for segments that belong to no connection
+    resets: RateLimit,
+    /// ACKs sent for segments outside the receive window
+    challenge_acks: RateLimit,
+    syn_cookies: SynCookies,
+    /// Key for initial sequence numbers, kept for as long as we run
+    isn_secret: u64,
+}
+
+impl Tcpd {
+    fn new(scheme_file: File, tcp_file: File, time_file: File) -> Self {
+        let mut rng = OsRng::new().expect("tcpd: failed to open RNG");
+        let syn_cookies = SynCookies::new(&mut rng);
+        let isn_secret = rng.gen();
+
+        Tcpd {
+            scheme_file: scheme_file,
+            tcp_file: tcp_file,
+            time_file: time_file,
+            timers: Timers::new(),
+            ports: BTreeMap::new(),
+            next_id: 1,
+            handles: BTreeMap::new(),
+            connections: BTreeMap::new(),
+            ack_now: BTreeSet::new(),
+            flush_now: BTreeSet::new(),
+            rng,
+            checksum_errors: 0,
+            offset_errors: 0,
+            flag_errors: 0,
+            window_resets: 0,
+            half_open: 0,
+            time_wait: TIME_WAIT,
+            resets: RateLimit::new(RESET_LIMIT),
+            challenge_acks: RateLimit::new(ACK_LIMIT),
+            syn_cookies,
+            isn_secret
+        }
+    }
+
+    fn scheme_event(&mut self) -> io::Result<()> {
+        loop {
+            let mut packet = Packet::default();
+            if self.scheme_file.read(&mut packet)? == 0 {
+                break;
+            }
+
+            let a = packet.a;
+            self.handle(&mut packet);
+            if packet.a == (-EWOULDBLOCK) as usize {
+                if let Some(mut handle) = self.handles.get_mut(&packet.b) {
+                    if let Handle::Tcp(ref mut handle) = *handle {
+                        match a {
+                            syscall::number::SYS_DUP => {
+                                packet.a = a;
+                                handle.todo_dup.push_back(packet);
+                            },
+                            syscall::number::SYS_READ => {
+                                packet.a = a;
+
+                                let timeout = match handle.read_timeout {
+                                    Some(read_timeout) => {
+                                        let mut time = TimeSpec::default();
+                                        syscall::clock_gettime(CLOCK_MONOTONIC, &mut time).map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+
+                                        let timeout = add_time(&time, &read_timeout);
+                                        self.time_file.write_all(&timeout)?;
+                                        Some(timeout)
+                                    },
+                                    None => None
+                                };
+
+                                handle.todo_read.push_back((timeout, packet));
+                            },
+                            // A full send buffer is reported to non-blocking writers
+                            syscall::number::SYS_WRITE if handle.flags & O_NONBLOCK == O_NONBLOCK => {
+                                self.scheme_file.write_all(&packet)?;
+                            },
+                            syscall::number::SYS_WRITE => {
+                                packet.a = a;
+
+                                let timeout = match handle.write_timeout {
+                                    Some(write_timeout) => {
+                                        let mut time = TimeSpec::default();
+                                        syscall::clock_gettime(CLOCK_MONOTONIC, &mut time).map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+
+                                        let timeout = add_time(&time, &write_timeout);
+                                        self.time_file.write_all(&timeout)?;
+                                        Some(timeout)
+                                    },
+                                    None => None
+                                };
+
+                                handle.todo_write.push_back((timeout, packet));
+                            },
+                            syscall::number::SYS_CLOSE => {
+                                packet.a = a;
+                                handle.todo_close.push_back(packet);
+                            },
+                            syscall::number::SYS_FSYNC if handle.flags & O_NONBLOCK == O_NONBLOCK => {
+                                self.scheme_file.write_all(&packet)?;
+                            },
+                            syscall::number::SYS_FSYNC => {
+                                packet.a = a;
+                                handle.todo_sync.push_back(packet);
+                            },
+                            _ => {
+                                self.scheme_file.write_all(&packet)?;
+                            }
+                        }
                     }
+                }
+            } else {
+                self.scheme_file.write_all(&packet)?;
+            }
+        }
 
-                    return Ok(len);
-                } else if handle.flags & O_NONBLOCK == O_NONBLOCK || handle.read_closed() {
-                    return Ok(0);
-                } else {
-                    return Err(Error::new(EWOULDBLOCK));
+        self.flush()?;
+
+        self.schedule()
+    }
+
+    /// Send what was written to the connections in flush_now. Small writes from the whole batch
+    /// are cut into as few segments as possible
+    fn flush(&mut self) -> io::Result<()> {
+        for id in mem::take(&mut self.flush_now) {
+            if let Some(&mut Handle::Tcp(ref mut handle)) = self.handles.get_mut(&id) {
+                let time = monotonic().map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+                handle.output(false, time, &mut self.tcp_file, &mut self.rng)?;
+                self.timers.set(id, TimerKind::Retransmit, handle.retransmit_deadline());
+                self.timers.start(id, TimerKind::Persist, handle.persist_deadline(&time));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn tcp_event(&mut self) -> io::Result<()> {
+        loop {
+            let mut bytes = [0; 65536];
+            let count = self.tcp_file.read(&mut bytes)?;
+            if count == 0 {
+                break;
+            }
+
+            // A read may hold several packets, each delimited by its total length field
+            let mut i = 0;
+            while i + mem::size_of::<Ipv4Header>() <= count {
+                let len = (bytes[i + 2] as usize) << 8 | bytes[i + 3] as usize;
+                if len < mem::size_of::<Ipv4Header>() || i + len > count {
+                    break;
                 }
-            },
-            Handle::Setting(file, kind) => {
-                (file, kind)
+
+                self.packet_event(&bytes[i .. i + len])?;
+
+                i += len;
             }
-        };
+        }
 
-        if let Handle::Tcp(ref mut handle) = *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
-            let get_timeout = |timeout: &Option<TimeSpec>, buf: &mut [u8]| -> Result<usize> {
-                if let Some(ref timespec) = *timeout {
-                    timespec.deref().read(buf).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))
-                } else {
-                    Ok(0)
+        // One cumulative ACK per connection for the whole burst
+        for id in mem::take(&mut self.ack_now) {
+            if let Some(&mut Handle::Tcp(ref mut handle)) = self.handles.get_mut(&id) {
+                if handle.ack_pending {
+                    handle.ack_pending = false;
+
+                    let tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                    let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                    self.tcp_file.write_all(&ip.to_bytes())?;
                 }
-            };
+            }
+        }
 
-            match kind {
-                SettingKind::Ttl => {
-                    if let Some(mut ttl) = buf.get_mut(0) {
-                        *ttl = handle.ttl;
-                        Ok(1)
-                    } else {
-                        Ok(0)
+        self.schedule()
+    }
+
+    /// Process one IP packet read from the ip: scheme
+    fn packet_event(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Some(ip) = Ipv4::from_bytes(bytes) {
+            if ! ip_checksum_ok(&ip) || ! tcp_checksum_ok(&ip) {
+                self.checksum_errors += 1;
+                return Ok(());
+            }
+
+            // Otherwise the options would be read from past the segment, or the header from its data
+            if ! data_offset_ok(&ip.data) {
+                self.offset_errors += 1;
+                return Ok(());
+            }
+
+            if let Some(tcp) = Tcp::from_bytes(&ip.data) {
+                if ! flags_ok(&tcp) {
+                    self.flag_errors += 1;
+                    return Ok(());
+                }
+
+                let connection = self.lookup_connection(&ip, &tcp);
+                if let Some(id) = connection {
+                    if self.connection_event(id, &ip, &tcp)? {
+                        self.remove_handle(id);
                     }
-                },
-                SettingKind::ReadTimeout => {
-                    get_timeout(&handle.read_timeout, buf)
-                },
-                SettingKind::WriteTimeout => {
-                    get_timeout(&handle.write_timeout, buf)
                 }
-            }
-        } else {
+
+                // An ACK returning one of our SYN cookies is taken as the SYN it answered
+                let cookie_syn = if connection.is_none() && tcp.header.flags.get() & (TCP_SYN | TCP_ACK | TCP_RST) == TCP_ACK {
+                    self.cookie_syn(&ip, &tcp)?
+                } else {
+                    None
+                };
+
+                if connection.is_none() && cookie_syn.is_none() && tcp.header.flags.get() & (TCP_SYN | TCP_ACK) != TCP_SYN {
+                    self.reset_unknown(&ip, &tcp)?;
+                }
+
+                if connection.is_none() && (cookie_syn.is_some() || tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_SYN) {
+                    let tcp = cookie_syn.unwrap_or(tcp);
+                    let mut new_handles = Vec::new();
+                    let mut listening = false;
+                    let mut cookie = None;
+
+                    for (id, handle) in self.handles.iter_mut() {
+                        if let Handle::Tcp(ref mut handle) = *handle {
+                            if handle.state == State::Listen && handle.matches(&ip, &tcp) {
+                                listening = true;
+
+                                // A retransmission of a SYN that is still waiting to be accepted is already queued
+                                if handle.data.iter().any(|(queued_ip, queued_tcp)| queued_ip.header.src == ip.header.src && queued_tcp.header.src.get() == tcp.header.src.get()) {
+                                    break;
+                                }
+
+                                // Half open connections are the cheapest thing to flood us with. Past
+                                // the limit no state is kept for the SYN, which is answered with a
+                                // cookie instead. A finished handshake is queued even so
+                                if self.half_open >= HALF_OPEN_LIMIT && tcp.header.flags.get() & TCP_ACK != TCP_ACK {
+                                    cookie = Some((handle.max_seg, handle.ttl));
+                                    break;
+                                }
+
+                                // With the backlog full the SYN is dropped, and the peer will retry it
+                                if handle.data.len() >= handle.backlog {
+                                    continue;
+                                }
+
+                                handle.data.push_back((ip.clone(), tcp.clone()));
+                                self.half_open += 1;
+
+                                while ! handle.todo_dup.is_empty() && ! handle.data.is_empty() {
+                                    let mut packet = handle.todo_dup.pop_front().unwrap();
+                                    let (ip, tcp) = handle.data.pop_front().unwrap();
+                                    self.half_open -= 1;
+
+                                    let time = monotonic().map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+                                    let mut new_handle = TcpHandle {
+                                        uid: handle.uid,
+                                        read_timeout: handle.read_timeout,
+                                        write_timeout: handle.write_timeout,
+                                        ttl: handle.ttl,
+                                        state: State::SynReceived,
+                                        snd_nxt: initial_sequence(self.isn_secret, (ip.header.dst, tcp.header.dst.get()), (ip.header.src, tcp.header.src.get()), &time),
+                                        rcv_nxt: tcp.header.sequence.get().wrapping_add(1),
+                                        send_buffer: handle.send_buffer,
+                                        backlog: handle.backlog,
+                                        syn_retries: handle.syn_retries,
+                                        syn_rto: handle.syn_rto,
+                                        max_retries: handle.max_retries,
+                                        rto_max: handle.rto_max,
+                                        time_wait: handle.time_wait,
+                                        fin_wait2: handle.fin_wait2,
+                                        linger: handle.linger,
+                                        remote_mss: cmp::min(send_mss(&tcp.options), handle.max_seg),
+                                        max_seg: handle.max_seg,
+                                        nodelay: handle.nodelay,
+                                        ecn: handle.ecn,
+                                        reuse_addr: handle.reuse_addr,
+                                        keepalive: handle.keepalive,
+                                        push_boundaries: handle.push_boundaries,
+                                        ecn_ok: tcp.header.flags.get() & (TCP_ECE | TCP_CWR) == TCP_ECE | TCP_CWR,
+                                        rcv_wscale: parse_wscale(&tcp.options).map(|_| local_wscale()),
+                                        snd_wscale: parse_wscale(&tcp.options).unwrap_or(0),
+                                        snd_wnd: tcp.header.window_size.get() as u32,
+                                        max_snd_wnd: tcp.header.window_size.get() as u32,
+                                        sack: parse_sack_permitted(&tcp.options),
+                                        timestamps: parse_timestamps(&tcp.options).is_some(),
+                                        ts_recent: parse_timestamps(&tcp.options).map_or(0, |(tsval, _)| tsval),
+                                        snd_wl1: tcp.header.sequence.get(),
+                                        ..TcpHandle::new(handle.local, (ip.header.src, tcp.header.src.get()), handle.flags)
+                                    };
+
+                                    new_handle.reset_cwnd();
+
+                                    if tcp.header.flags.get() & TCP_ACK == TCP_ACK {
+                                        // The SYN-ACK was a cookie, and its ACK has already finished the handshake
+                                        new_handle.state = State::Established;
+                                        new_handle.snd_nxt = tcp.header.ack_num.get();
+                                        new_handle.snd_una = new_handle.snd_nxt;
+                                    } else {
+                                        new_handle.snd_una = new_handle.snd_nxt;
+                                        new_handle.rto = new_handle.syn_rto;
+
+                                        new_handle.ts_recent_age = timestamp(&time);
+                                        let tcp = new_handle.create_tcp(TCP_SYN | TCP_ACK, Vec::new());
+                                        let ip = new_handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                                        self.tcp_file.write_all(&ip.to_bytes())?;
+
+                                        new_handle.push_unacked(TCP_SYN | TCP_ACK, Vec::new(), time);
+                                        new_handle.advance_snd(1);
+                                    }
+
+                                    let queued = handle.data.len();
+                                    handle.data.retain(|&(ref ip, ref tcp)| {
+                                        if new_handle.matches(ip, tcp) {
+                                            false
+                                        } else {
+                                            true
+                                        }
+                                    });
+                                    self.half_open -= queued - handle.data.len();
+
+                                    if let Some(mut port) = self.ports.get_mut(&handle.local.1) {
+                                        *port = *port + 1;
+                                    }
+
+                                    let id = self.next_id;
+                                    self.next_id += 1;
+
+                                    packet.a = id;
+
+                                    new_handles.push((packet, Handle::Tcp(Box::new(new_handle))));
+                                }
+
+                                if handle.events & EVENT_READ == EVENT_READ {
+                                    if let Some(&(ref _ip, ref tcp)) = handle.data.get(0) {
+                                        self.scheme_file.write_all(&Packet {
+                                            id: 0,
+                                            pid: 0,
+                                            uid: 0,
+                                            gid: 0,
+                                            a: syscall::number::SYS_FEVENT,
+                                            b: *id,
+                                            c: EVENT_READ,
+                                            d: tcp.data.len()
+                                        })?;
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    for (packet, new_handle) in new_handles {
+                        self.insert_handle(packet.a, new_handle);
+                        self.scheme_file.write_all(&packet)?;
+                    }
+
+                    if let Some((max_seg, ttl)) = cookie {
+                        let time = monotonic().map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+                        self.syn_cookies.rotate(&time, &mut self.rng);
+
+                        let mss = cmp::min(send_mss(&tcp.options), max_seg);
+                        let cookie = self.syn_cookies.make(&ip, &tcp, mss);
+                        let ip = create_cookie_syn_ack(&ip, &tcp, cookie, COOKIE_MSS[(cookie >> 24 & 0x7) as usize], ttl, self.rng.gen());
+                        self.tcp_file.write_all(&ip.to_bytes())?;
+                    }
+
+                    // Nobody is listening, refuse the connection instead of letting it time out
+                    if ! listening {
+                        self.reset_unknown(&ip, &tcp)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The SYN an ACK stands for if it acknowledges one of our SYN cookies, queued by a listener as
+    /// a SYN-ACK to show that the handshake is already finished
+    fn cookie_syn(&mut self, ip: &Ipv4, tcp: &Tcp) -> io::Result<Option<Tcp>> {
+        let listening = self.handles.values().any(|handle| match *handle {
+            Handle::Tcp(ref handle) => handle.state == State::Listen && handle.matches(ip, tcp),
+            _ => false
+        });
+        if ! listening {
+            return Ok(None);
+        }
+
+        let time = monotonic().map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+        self.syn_cookies.rotate(&time, &mut self.rng);
+
+        let mss = match self.syn_cookies.check(ip, tcp) {
+            Some(mss) => mss,
+            None => return Ok(None)
+        };
+
+        let options = mss_option(mss).to_vec();
+        Ok(Some(Tcp {
+            header: TcpHeader {
+                src: tcp.header.src,
+                dst: tcp.header.dst,
+                sequence: n32::new(tcp.header.sequence.get().wrapping_sub(1)),
+                ack_num: tcp.header.ack_num,
+                flags: n16::new((((mem::size_of::<TcpHeader>() + options.len()) << 10) & 0xF000) as u16 | TCP_SYN | TCP_ACK),
+                window_size: tcp.header.window_size,
+                checksum: Checksum { data: 0 },
+                urgent_pointer: n16::new(0),
+            },
+            options,
+            data: Vec::new()
+        }))
+    }
+
+    /// Tell the sender of a segment that belongs to no connection that it is gone, at most
+    /// `RESET_LIMIT` times a second so that we cannot be used to amplify a flood
+    fn reset_unknown(&mut self, ip: &Ipv4, tcp: &Tcp) -> io::Result<()> {
+        // Never answer a reset, or anything that was not sent to us alone
+        if tcp.header.flags.get() & TCP_RST == TCP_RST || ip.header.dst == Ipv4Addr::BROADCAST {
+            return Ok(());
+        }
+
+        let time = monotonic().map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+        if self.resets.allow(&time) {
+            let ip = create_reset(ip, tcp, self.rng.gen());
+            self.tcp_file.write_all(&ip.to_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Find the connection a segment belongs to
+    fn lookup_connection(&self, ip: &Ipv4, tcp: &Tcp) -> Option<usize> {
+        let remote = (ip.header.src.bytes, tcp.header.src.get());
+        // Connections without a local address are indexed under the wildcard address
+        let locals = [ip.header.dst.bytes, Ipv4Addr::NULL.bytes];
+
+        for local in locals.iter() {
+            if let Some(&id) = self.connections.get(&(*local, tcp.header.dst.get(), remote.0, remote.1)) {
+                if let Some(Handle::Tcp(handle)) = self.handles.get(&id) {
+                    // A closed handle is only kept to report its error, the segment is for
+                    // whatever comes next on the same addresses
+                    if handle.state != State::Closed && handle.matches(ip, tcp) {
+                        return Some(id);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Process a segment for an existing connection, returning true if the handle should be removed
+    fn connection_event(&mut self, id: usize, ip: &Ipv4, tcp: &Tcp) -> io::Result<bool> {
+        let mut closing = false;
+
+        if let Some(&mut Handle::Tcp(ref mut handle)) = self.handles.get_mut(&id) {
+            if tcp.header.flags.get() & TCP_RST == TCP_RST {
+                // Only a reset exactly at rcv_nxt is believed, so a blind attacker has to guess it
+                // (RFC 5961 section 3). Elsewhere in the window it gets a challenge ACK, which the
+                // real peer answers with that exact reset
+                let acceptable = if handle.state == State::SynSent {
+                    tcp.header.flags.get() & TCP_ACK == TCP_ACK && tcp.header.ack_num.get() == handle.snd_nxt
+                } else if tcp.header.sequence.get() == handle.rcv_nxt {
+                    true
+                } else {
+                    if seq_in_window(tcp.header.sequence.get(), handle.rcv_nxt, cmp::max(handle.window(), 1)) {
+                        let time = monotonic().map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+                        if self.challenge_acks.allow(&time) {
+                            handle.ack_pending = false;
+
+                            let tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                            let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                            self.tcp_file.write_all(&ip.to_bytes())?;
+                        }
+                    } else {
+                        self.window_resets += 1;
+                    }
+                    false
+                };
+
+                if acceptable {
+                    // A refused connect keeps its handle so the error can be reported
+                    if handle.state == State::SynSent {
+                        handle.fail_connection(id, ECONNREFUSED, &mut self.scheme_file)?;
+                        self.timers.cancel_all(id);
+                        return Ok(false);
+                    }
+
+                    // So does a reset connection, until the application closes it. Nothing that
+                    // was received or buffered for sending is delivered any more
+                    handle.data.clear();
+                    handle.send_buf.clear();
+                    self.half_open -= handle.half_open();
+                    handle.fail_connection(id, ECONNRESET, &mut self.scheme_file)?;
+                    self.timers.cancel_all(id);
+
+                    return Ok(handle.closed);
+                }
+
+                return Ok(false);
+            }
+
+            let was_established = handle.state == State::Established;
+            let mut progress = false;
+
+            let time = monotonic().map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+
+            // Anything from the peer shows it is alive
+            if handle.keepalive {
+                handle.keepalive_probes = 0;
+                self.timers.arm(id, TimerKind::KeepAlive, add_time(&time, &millis(KEEPALIVE_IDLE)));
+            }
+
+            // The peer did not see our SYN-ACK and retransmitted its SYN, so send the SYN-ACK again
+            // without moving on
+            if handle.state == State::SynReceived && tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_SYN
+                && tcp.header.sequence.get().wrapping_add(1) == handle.rcv_nxt {
+                let mut tcp = handle.create_tcp(TCP_SYN | TCP_ACK, Vec::new());
+                tcp.header.sequence.set(handle.snd_una);
+                let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                self.tcp_file.write_all(&ip.to_bytes())?;
+
+                return Ok(false);
+            }
+
+            // Once synchronized, a segment outside our window, or acknowledging something we never
+            // sent, is answered with an ACK restating where we are and then dropped (RFC 793
+            // section 3.9). TimeWait answers retransmitted FINs itself.
+            //
+            // So is any SYN, wherever it falls (RFC 5961 section 4). A peer that lost the connection
+            // and is reconnecting from the same port answers the ACK with a RST, which clears this end.
+            //
+            // So is a segment with a timestamp older than the last one taken in order (PAWS, RFC 7323
+            // section 5), which can only be an old duplicate. After 24 days without a refresh the
+            // last timestamp may have wrapped past the peer's current one, so it is no longer trusted
+            let synchronized = ! matches!(handle.state, State::Listen | State::SynSent | State::SynReceived | State::TimeWait | State::Closed);
+            let paws = handle.timestamps && timestamp(&time).wrapping_sub(handle.ts_recent_age) < PAWS_IDLE
+                && parse_timestamps(&tcp.options).is_some_and(|(tsval, _)| seq_lt(tsval, handle.ts_recent));
+            if synchronized && (! handle.acceptable(tcp)
+                || paws
+                || tcp.header.flags.get() & TCP_SYN == TCP_SYN
+                || (tcp.header.flags.get() & TCP_ACK == TCP_ACK && seq_gt(tcp.header.ack_num.get(), handle.snd_nxt))) {
+                if self.challenge_acks.allow(&time) {
+                    handle.ack_pending = false;
+
+                    let tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                    let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                    self.tcp_file.write_all(&ip.to_bytes())?;
+                }
+
+                return Ok(false);
+            }
+
+            if tcp.header.flags.get() & TCP_ACK == TCP_ACK {
+                let ack_num = tcp.header.ack_num.get();
+
+                // An ACK for something we never sent means the peer is confused, reset it
+                if seq_gt(ack_num, handle.snd_nxt) || (handle.state == State::SynSent && seq_leq(ack_num, handle.snd_una)) {
+                    let mut tcp = handle.create_tcp(TCP_RST, Vec::new());
+                    tcp.header.sequence.set(ack_num);
+                    let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                    self.tcp_file.write_all(&ip.to_bytes())?;
+
+                    return Ok(false);
+                }
+
+                // Stale ACKs, from before what has already been acknowledged, are ignored
+                if seq_leq(handle.snd_una, ack_num) {
+                    // Our SYN offered timestamps, so a SYN-ACK may already echo one
+                    let tsecr = if handle.timestamps || handle.state == State::SynSent {
+                        parse_timestamps(&tcp.options).map(|(_, tsecr)| tsecr)
+                    } else {
+                        None
+                    };
+
+                    if handle.acknowledge(ack_num, tsecr, &time) {
+                        progress = true;
+                        self.timers.set(id, TimerKind::Retransmit, handle.retransmit_deadline());
+                    }
+
+                    handle.update_snd_wnd(tcp);
+                    handle.max_snd_wnd = cmp::max(handle.max_snd_wnd, handle.snd_wnd);
+                    if handle.snd_wnd > 0 {
+                        handle.probes = 0;
+                    }
+
+                    if handle.sack {
+                        handle.mark_sacked(&parse_sack(&tcp.options));
+                        if handle.retransmit_lost(time, &mut self.tcp_file, &mut self.rng)? {
+                            self.timers.set(id, TimerKind::Retransmit, handle.retransmit_deadline());
+                        }
+                    }
+                }
+            }
+
+            if handle.ecn_ok && tcp.header.flags.get() & TCP_SYN != TCP_SYN {
+                // Keep echoing congestion marked on the way to us until the peer has reacted
+                if tcp.header.flags.get() & TCP_CWR == TCP_CWR {
+                    handle.ece = false;
+                }
+                if ip.header.services & IP_ECN == IP_CE {
+                    handle.ece = true;
+                }
+
+                // Congestion was marked on the way to the peer, back off as for a loss but
+                // without retransmitting, at most once per window
+                if tcp.header.flags.get() & (TCP_ACK | TCP_ECE) == TCP_ACK | TCP_ECE
+                    && handle.ecn_recover.is_none_or(|recover| seq_gt(tcp.header.ack_num.get(), recover)) {
+                    let mss = handle.remote_mss as u32;
+                    handle.ssthresh = cmp::max(handle.cwnd / 2, 2 * mss);
+                    handle.cwnd = handle.ssthresh;
+                    handle.cwr = true;
+                    handle.ecn_recover = Some(handle.snd_nxt);
+                }
+            }
+
+            // Echo the peer's latest timestamp, but not one from an old duplicate
+            if handle.timestamps && seq_leq(tcp.header.sequence.get(), handle.rcv_nxt) {
+                if let Some((tsval, _)) = parse_timestamps(&tcp.options) {
+                    handle.ts_recent = tsval;
+                    handle.ts_recent_age = timestamp(&time);
+                }
+            }
+
+            // The ACK completing the handshake is the first segment after the peer's SYN, so anything
+            // else is stale or injected. It may already carry data or a FIN, which are then taken
+            // below as for any other established connection
+            if handle.state == State::SynReceived && tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_ACK
+                && handle.snd_una == handle.snd_nxt && tcp.header.sequence.get() == handle.rcv_nxt {
+                handle.state = State::Established;
+                self.half_open -= 1;
+            }
+
+            match handle.state {
+                State::SynSent if tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK && tcp.header.ack_num.get() == handle.snd_nxt => {
+                    handle.state = State::Established;
+                    handle.rcv_nxt = tcp.header.sequence.get().wrapping_add(1);
+                    handle.remote_mss = cmp::min(send_mss(&tcp.options), handle.max_seg);
+                    handle.reset_cwnd();
+                    // Scaling is only used if the peer echoes the option
+                    match parse_wscale(&tcp.options) {
+                        Some(shift) => handle.snd_wscale = shift,
+                        None => handle.rcv_wscale = None
+                    }
+                    handle.sack = parse_sack_permitted(&tcp.options);
+                    handle.ecn_ok = handle.ecn && tcp.header.flags.get() & (TCP_ECE | TCP_CWR) == TCP_ECE;
+                    match parse_timestamps(&tcp.options) {
+                        Some((tsval, _)) => {
+                            handle.timestamps = true;
+                            handle.ts_recent = tsval;
+                            handle.ts_recent_age = timestamp(&time);
+                        },
+                        None => handle.timestamps = false
+                    }
+
+                    let tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                    let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                    self.tcp_file.write_all(&ip.to_bytes())?;
+                },
+                // Data keeps arriving after we have shut down writing, until the peer sends its own FIN
+                State::Established | State::FinWait1 | State::FinWait2 if tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_ACK => {
+                    let seq = tcp.header.sequence.get();
+                    let end = seq.wrapping_add(tcp.data.len() as u32);
+
+                    if ! tcp.data.is_empty() {
+                        // Take only the part we have not received yet, skipping duplicates.
+                        // Anything past a gap waits for the gap to be filled
+                        let gap = ! handle.reassembly.is_empty();
+                        let mut accepted = false;
+                        if seq_leq(seq, handle.rcv_nxt) && seq_gt(end, handle.rcv_nxt) {
+                            let mut tcp = tcp.clone();
+                            tcp.data.drain(.. handle.rcv_nxt.wrapping_sub(seq) as usize);
+
+                            // Nothing past our window is taken, so a probe while it is closed
+                            // only gets an ACK restating the window
+                            tcp.data.truncate(handle.window() as usize);
+
+                            if ! tcp.data.is_empty() {
+                                let start = handle.rcv_nxt;
+                                let next = handle.rcv_nxt.wrapping_add(tcp.data.len() as u32);
+                                handle.take_reassembled(next, &mut tcp.data);
+
+                                handle.advance_rcv(tcp.data.len() as u32);
+                                handle.bytes_received += tcp.data.len() as u64;
+
+                                // The byte before the urgent pointer is taken out of band (RFC 6093 section 4)
+                                let urgent = tcp.header.urgent_pointer.get();
+                                if tcp.header.flags.get() & TCP_URG == TCP_URG && urgent > 0 {
+                                    let mark = seq.wrapping_add(urgent as u32 - 1).wrapping_sub(start) as usize;
+                                    if mark < tcp.data.len() {
+                                        handle.oob = Some(tcp.data.remove(mark));
+                                    }
+                                }
+
+                                if ! handle.read_shutdown && ! tcp.data.is_empty() {
+                                    handle.rcv_wnd = handle.rcv_wnd.saturating_sub(tcp.data.len() as u32);
+                                    handle.data.push_back((ip.clone(), tcp));
+                                }
+                                accepted = true;
+                            }
+                        } else if seq_gt(seq, handle.rcv_nxt) {
+                            let limit = handle.rcv_nxt.wrapping_add(handle.window());
+                            if seq_lt(seq, limit) {
+                                let len = cmp::min(tcp.data.len(), limit.wrapping_sub(seq) as usize);
+                                handle.queue_reassembly(seq, tcp.data[.. len].to_vec());
+                            }
+                        }
+
+                        // Hold back the ACK for one segment, unless the window has closed or a gap
+                        // is being filled. Duplicate, out of order and probe segments are answered at
+                        // the end of this pass so the peer can recover, with one ACK covering the
+                        // whole burst
+                        if accepted && ! gap && ! handle.ack_pending && handle.window() > 0 {
+                            handle.ack_pending = true;
+                            self.timers.arm(id, TimerKind::DelayedAck, add_time(&time, &millis(DELAYED_ACK)));
+                        } else {
+                            handle.ack_pending = true;
+                            self.ack_now.insert(id);
+                        }
+                    }
+
+                    // The FIN only counts once everything before it has arrived
+                    if tcp.header.flags.get() & TCP_FIN == TCP_FIN && end == handle.rcv_nxt {
+                        handle.advance_rcv(1);
+                        handle.ack_pending = false;
+
+                        handle.state = match handle.state {
+                            State::Established => State::CloseWait,
+                            // Both sides are closing at once, ours still waits for its ACK
+                            State::FinWait1 if handle.snd_una != handle.snd_nxt => State::Closing,
+                            _ => State::TimeWait
+                        };
+
+                        let tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                        let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                        self.tcp_file.write_all(&ip.to_bytes())?;
+
+                        if handle.state == State::TimeWait {
+                            self.timers.cancel(id, TimerKind::FinWait2);
+                            self.timers.arm(id, TimerKind::TimeWait, add_time(&time, &millis(handle.time_wait)));
+                        }
+                    } else if handle.state == State::FinWait1 && handle.snd_una == handle.snd_nxt {
+                        // Our FIN is acknowledged once nothing is outstanding
+                        handle.state = State::FinWait2;
+
+                        // An application that only shut down writing may wait on the peer as long as it likes
+                        if handle.closed {
+                            self.timers.arm(id, TimerKind::FinWait2, add_time(&time, &millis(handle.fin_wait2)));
+                        }
+                    }
+                },
+                State::Closing if tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_ACK && handle.snd_una == handle.snd_nxt => {
+                    handle.state = State::TimeWait;
+
+                    self.timers.arm(id, TimerKind::TimeWait, add_time(&time, &millis(handle.time_wait)));
+                },
+                // The peer did not see our last ACK and retransmitted its FIN
+                State::TimeWait => if tcp.header.flags.get() & (TCP_SYN | TCP_FIN) == TCP_FIN {
+                    let tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                    let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                    self.tcp_file.write_all(&ip.to_bytes())?;
+
+                    self.timers.arm(id, TimerKind::TimeWait, add_time(&time, &millis(handle.time_wait)));
+                } else if tcp.header.flags.get() & TCP_SYN != TCP_SYN && ! tcp.data.is_empty() && self.challenge_acks.allow(&time) {
+                    // Old data still in the network only needs to hear where the connection ended
+                    let tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                    let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                    self.tcp_file.write_all(&ip.to_bytes())?;
+                },
+                State::LastAck if tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_ACK && handle.snd_una == handle.snd_nxt => {
+                    handle.state = State::Closed;
+                    // Still open if the application only shut down writing
+                    closing = handle.closed;
+                },
+                _ => ()
+            }
+
+            let window = handle.window();
+            while ! handle.todo_read.is_empty() && (! handle.data.is_empty() || handle.read_closed()) {
+                let (_timeout, mut packet) = handle.todo_read.pop_front().unwrap();
+                let buf = unsafe { slice::from_raw_parts_mut(packet.c as *mut u8, packet.d) };
+                packet.a = handle.read_data(buf);
+
+                self.scheme_file.write_all(&packet)?;
+            }
+
+            handle.update_window();
+
+            // Tell the peer once the application has drained enough to open the window
+            if handle.window() > window && ! handle.read_closed() {
+                handle.ack_pending = false;
+
+                let tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                self.tcp_file.write_all(&ip.to_bytes())?;
+            }
+
+            // Send what the ACK has made room for
+            if ! handle.send_buf.is_empty() {
+                handle.output(false, time, &mut self.tcp_file, &mut self.rng)?;
+                self.timers.set(id, TimerKind::Retransmit, handle.retransmit_deadline());
+            }
+
+            // Waiting writes are all buffered first, so they share segments
+            let mut written = false;
+            let writable = handle.state == State::Established || handle.state == State::CloseWait;
+            while ! handle.todo_write.is_empty() && handle.send_buf.len() < handle.send_buffer && writable {
+                let (_timeout, mut packet) = handle.todo_write.pop_front().unwrap();
+                let buf = unsafe { slice::from_raw_parts(packet.c as *const u8, packet.d) };
+
+                packet.a = handle.buffer_data(buf);
+                written = true;
+
+                self.scheme_file.write_all(&packet)?;
+            }
+
+            if written {
+                handle.output(false, time, &mut self.tcp_file, &mut self.rng)?;
+                self.timers.set(id, TimerKind::Retransmit, handle.retransmit_deadline());
+            }
+
+            self.timers.start(id, TimerKind::Persist, handle.persist_deadline(&time));
+
+            if handle.events & EVENT_READ == EVENT_READ {
+                if let Some(&(ref _ip, ref tcp)) = handle.data.get(0) {
+                    self.scheme_file.write_all(&Packet {
+                        id: 0,
+                        pid: 0,
+                        uid: 0,
+                        gid: 0,
+                        a: syscall::number::SYS_FEVENT,
+                        b: id,
+                        c: EVENT_READ,
+                        d: tcp.data.len()
+                    })?;
+                }
+            }
+
+            // Writes can go ahead once the connection is up, and again whenever an ACK frees buffer space
+            if handle.events & EVENT_WRITE == EVENT_WRITE && writable && (progress || ! was_established) {
+                let space = handle.send_buffer.saturating_sub(handle.send_buf.len());
+                if space > 0 {
+                    self.scheme_file.write_all(&Packet {
+                        id: 0,
+                        pid: 0,
+                        uid: 0,
+                        gid: 0,
+                        a: syscall::number::SYS_FEVENT,
+                        b: id,
+                        c: EVENT_WRITE,
+                        d: space
+                    })?;
+                }
+            }
+
+            // A lingering close returns once everything it sent is acknowledged
+            if handle.closed && handle.snd_una == handle.snd_nxt {
+                self.timers.cancel(id, TimerKind::Linger);
+
+                while let Some(mut packet) = handle.todo_close.pop_front() {
+                    packet.a = 0;
+                    self.scheme_file.write_all(&packet)?;
+                }
+            }
+
+            if handle.synced() {
+                while let Some(mut packet) = handle.todo_sync.pop_front() {
+                    packet.a = 0;
+                    self.scheme_file.write_all(&packet)?;
+                }
+            }
+        }
+
+        Ok(closing)
+    }
+
+    fn time_event(&mut self) -> io::Result<()> {
+        let mut time = TimeSpec::default();
+        if self.time_file.read(&mut time)? < mem::size_of::<TimeSpec>() {
+            return Err(io::Error::from_raw_os_error(EINVAL));
+        }
+
+        for (_id, handle) in self.handles.iter_mut() {
+            if let Handle::Tcp(ref mut handle) = *handle {
+                let mut i = 0;
+                while i < handle.todo_read.len() {
+                    if let Some(timeout) =  handle.todo_read.get(i).map(|e| e.0.clone()).unwrap_or(None) {
+                        if time_reached(&time, &timeout) {
+                            let (_timeout, mut packet) = handle.todo_read.remove(i).unwrap();
+                            packet.a = (-ETIMEDOUT) as usize;
+                            self.scheme_file.write_all(&packet)?;
+                        } else {
+                            i += 1;
+                        }
+                    } else {
+                        i += 1;
+                    }
+                }
+
+                let mut i = 0;
+                while i < handle.todo_write.len() {
+                    if let Some(timeout) = handle.todo_write.get(i).map(|e| e.0.clone()).unwrap_or(None) {
+                        if time_reached(&time, &timeout) {
+                            let (_timeout, mut packet) = handle.todo_write.remove(i).unwrap();
+                            packet.a = (-ETIMEDOUT) as usize;
+                            self.scheme_file.write_all(&packet)?;
+                        } else {
+                            i += 1;
+                        }
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+        }
+
+        for (file, kind) in self.timers.expire(&time) {
+            match kind {
+                TimerKind::Retransmit => self.retransmit(file, &time)?,
+                TimerKind::DelayedAck => {
+                    if let Some(&mut Handle::Tcp(ref mut handle)) = self.handles.get_mut(&file) {
+                        if handle.ack_pending {
+                            handle.ack_pending = false;
+
+                            let tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                            let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                            self.tcp_file.write_all(&ip.to_bytes())?;
+                        }
+                    }
+                },
+                TimerKind::TimeWait => {
+                    let closed = match self.handles.get_mut(&file) {
+                        Some(&mut Handle::Tcp(ref mut handle)) => {
+                            // A handle that only shut down writing stays around for the application to close
+                            handle.state = State::Closed;
+                            handle.closed
+                        },
+                        _ => true
+                    };
+
+                    if closed {
+                        self.remove_handle(file);
+                    }
+                },
+                TimerKind::FinWait2 => {
+                    // The peer never closed its side, reset it in case it is still there
+                    if let Some(Handle::Tcp(handle)) = self.remove_handle(file) {
+                        let tcp = handle.create_tcp(TCP_RST | TCP_ACK, Vec::new());
+                        let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                        self.tcp_file.write_all(&ip.to_bytes())?;
+                    }
+                },
+                TimerKind::KeepAlive => {
+                    let mut remove = false;
+
+                    if let Some(&mut Handle::Tcp(ref mut handle)) = self.handles.get_mut(&file) {
+                        let probing = match handle.state {
+                            State::Established | State::CloseWait | State::FinWait2 => handle.keepalive,
+                            _ => false
+                        };
+
+                        if ! probing {
+                            // Nothing to do until the connection is established again
+                        } else if ! handle.unacked.is_empty() {
+                            // Retransmission already finds out whether the peer is there
+                            self.timers.arm(file, TimerKind::KeepAlive, add_time(&time, &millis(KEEPALIVE_IDLE)));
+                        } else if handle.keepalive_probes >= KEEPALIVE_PROBES {
+                            handle.give_up(file, &mut self.tcp_file, &mut self.rng, &mut self.scheme_file)?;
+                            remove = handle.closed;
+                        } else {
+                            // A segment from before snd_nxt is outside the window, so the peer answers it with an ACK
+                            let mut tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                            tcp.header.sequence.set(handle.snd_nxt.wrapping_sub(1));
+                            let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                            self.tcp_file.write_all(&ip.to_bytes())?;
+
+                            handle.keepalive_probes += 1;
+                            self.timers.arm(file, TimerKind::KeepAlive, add_time(&time, &millis(KEEPALIVE_INTERVAL)));
+                        }
+                    }
+
+                    if remove {
+                        self.remove_handle(file);
+                    }
+                },
+                TimerKind::Persist => {
+                    if let Some(&mut Handle::Tcp(ref mut handle)) = self.handles.get_mut(&file) {
+                        if handle.persist_deadline(&time).is_some() {
+                            if handle.snd_wnd > 0 {
+                                // The window is open but was too small to be worth filling. Rather
+                                // than hold the data any longer, send what fits
+                                let len = cmp::min(cmp::min(handle.send_buf.len(), handle.usable_window()), handle.segment_size());
+                                if len > 0 {
+                                    handle.send_segment(len, time, &mut self.tcp_file, &mut self.rng)?;
+                                    self.timers.set(file, TimerKind::Retransmit, handle.retransmit_deadline());
+                                }
+                            } else {
+                                // A segment from before snd_una is outside the window, so the peer
+                                // answers it with an ACK restating its window
+                                let mut tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                                tcp.header.sequence.set(handle.snd_una.wrapping_sub(1));
+                                let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                                self.tcp_file.write_all(&ip.to_bytes())?;
+
+                                handle.probes = handle.probes.saturating_add(1);
+                            }
+
+                            self.timers.set(file, TimerKind::Persist, handle.persist_deadline(&time));
+                        }
+                    }
+                },
+                TimerKind::Linger => {
+                    // The peer did not take everything in time, abort instead of closing gracefully
+                    if let Some(Handle::Tcp(mut handle)) = self.remove_handle(file) {
+                        let tcp = handle.create_tcp(TCP_RST | TCP_ACK, Vec::new());
+                        let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                        self.tcp_file.write_all(&ip.to_bytes())?;
+
+                        while let Some(mut packet) = handle.todo_close.pop_front() {
+                            packet.a = 0;
+                            self.scheme_file.write_all(&packet)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.schedule()
+    }
+
+    /// Retransmit the oldest unacknowledged segment, or give up on the connection
+    fn retransmit(&mut self, file: usize, time: &TimeSpec) -> io::Result<()> {
+        let mut remove = false;
+
+        if let Some(&mut Handle::Tcp(ref mut handle)) = self.handles.get_mut(&file) {
+            let resend = handle.unacked.front().map(|segment| (segment.seq, segment.flags, segment.data.clone()));
+
+            if let Some((seq, flags, data)) = resend {
+                if handle.state == State::SynSent && handle.retries >= handle.syn_retries {
+                    handle.fail_connection(file, ETIMEDOUT, &mut self.scheme_file)?;
+                } else if handle.state == State::SynReceived && handle.retries >= handle.syn_retries {
+                    self.half_open -= 1;
+                    handle.fail_connection(file, ETIMEDOUT, &mut self.scheme_file)?;
+                    remove = handle.closed;
+                } else if handle.retries >= handle.max_retries {
+                    handle.give_up(file, &mut self.tcp_file, &mut self.rng, &mut self.scheme_file)?;
+                    remove = handle.closed;
+                } else {
+                    let mut tcp = handle.create_tcp(flags, data);
+                    tcp.header.sequence.set(seq);
+                    handle.set_urgent(&mut tcp);
+                    let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                    self.tcp_file.write_all(&ip.to_bytes())?;
+
+                    // The peer may have discarded data it SACKed, so after a timeout nothing is
+                    // taken as received until it is acknowledged or reported again (RFC 2018 section 8)
+                    for segment in handle.unacked.iter_mut() {
+                        segment.sacked = false;
+                    }
+
+                    if let Some(segment) = handle.unacked.front_mut() {
+                        segment.sent = *time;
+                        segment.retransmitted = true;
+                    }
+                    handle.retries += 1;
+                    handle.retransmits += 1;
+                    handle.rto = cmp::min(handle.rto * 2, handle.rto_max);
+
+                    // A timeout is a sign of congestion, start over with slow start
+                    let mss = handle.remote_mss as u32;
+                    handle.ssthresh = cmp::max(handle.cwnd / 2, 2 * mss);
+                    handle.cwnd = mss;
+                    self.timers.set(file, TimerKind::Retransmit, handle.retransmit_deadline());
+                }
+            }
+        }
+
+        if remove {
+            self.remove_handle(file);
+        }
+
+        Ok(())
+    }
+
+    /// Ask the time: scheme to wake us for the earliest timer
+    fn schedule(&mut self) -> io::Result<()> {
+        if let Some(deadline) = self.timers.reschedule() {
+            self.time_file.write_all(&deadline)?;
+        }
+
+        Ok(())
+    }
+
+    /// Insert a handle, arming the retransmission timer for anything it has already sent
+    fn insert_handle(&mut self, file: usize, handle: Handle) {
+        if let Handle::Tcp(ref handle) = handle {
+            self.half_open += handle.half_open();
+            self.timers.set(file, TimerKind::Retransmit, handle.retransmit_deadline());
+
+            if handle.state != State::Listen {
+                // A new connection takes over from one that was closed, as lookup_connection skips it
+                let handles = &self.handles;
+                let entry = self.connections.entry(handle.key()).or_insert(file);
+                if let Some(Handle::Tcp(other)) = handles.get(entry) {
+                    if other.state == State::Closed && handle.state != State::Closed {
+                        *entry = file;
+                    }
+                }
+            }
+        }
+
+        self.handles.insert(file, handle);
+    }
+
+    /// Remove a handle, cancelling its timers and dropping its reference to the local port
+    fn remove_handle(&mut self, file: usize) -> Option<Handle> {
+        self.timers.cancel_all(file);
+
+        let handle = self.handles.remove(&file);
+
+        if let Some(Handle::Tcp(ref handle)) = handle {
+            self.half_open -= handle.half_open();
+
+            if self.connections.get(&handle.key()) == Some(&file) {
+                self.connections.remove(&handle.key());
+
+                // Hand the connection over to a duplicate of the handle, if there is one
+                let key = handle.key();
+                let next = self.handles.iter().find(|&(_, other)| match *other {
+                    Handle::Tcp(ref other) => other.state != State::Listen && other.key() == key,
+                    _ => false
+                }).map(|(&id, _)| id);
+
+                if let Some(next) = next {
+                    self.connections.insert(key, next);
+                }
+            }
+
+            let remove = if let Some(port) = self.ports.get_mut(&handle.local.1) {
+                *port -= 1;
+                *port == 0
+            } else {
+                false
+            };
+
+            if remove {
+                self.ports.remove(&handle.local.1);
+            }
+        }
+
+        handle
+    }
+
+    /// Whether a port in use can be bound again by a handle that allows reuse. Every handle
+    /// holding it must be winding down without sharing the new connection, or be a listener that
+    /// allows reuse too, as must the new handle
+    fn port_reusable(&self, local: (Ipv4Addr, u16), remote: (Ipv4Addr, u16)) -> bool {
+        let key = (local.0.bytes, local.1, remote.0.bytes, remote.1);
+        let listen = remote.1 == 0;
+
+        self.handles.values().all(|handle| match *handle {
+            Handle::Tcp(ref handle) if handle.local.1 == local.1 => match handle.state {
+                State::Listen => listen && handle.reuse_addr,
+                State::TimeWait | State::Closed => handle.key() != key,
+                // Left behind by an application that has gone away
+                _ => handle.closed && handle.key() != key
+            },
+            _ => true
+        })
+    }
+
+    fn inner_dup(&mut self, file: usize, path: &str) -> Result<Handle> {
+        if let Handle::Empty(ref handle) = *self.handles.get(&file).ok_or(Error::new(EBADF))? {
+            if path == "connections" {
+                return Ok(Handle::Connections(self.list_connections(handle.privileged, handle.uid), 0));
+            } else if path == "reuseaddr" {
+                return Ok(Handle::Setting(file, SettingKind::ReuseAddr));
+            } else if path == "time_wait" {
+                return Ok(Handle::Setting(file, SettingKind::TimeWait));
+            } else if path == "maxseg" {
+                return Ok(Handle::Setting(file, SettingKind::MaxSeg));
+            } else if handle.reuse_addr {
+                let mut parts = path.split("/");
+                let remote = parse_socket(parts.next().unwrap_or(""));
+                let local = parse_socket(parts.next().unwrap_or(""));
+
+                if local.1 != 0 && ! self.port_reusable(local, remote) {
+                    return Err(Error::new(EADDRINUSE));
+                }
+            }
+        }
+
+        Ok(match *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
+            Handle::Empty(ref handle) => {
+                if path.is_empty() {
+                    Handle::Empty(EmptyHandle {
+                        privileged: handle.privileged,
+                        uid: handle.uid,
+                        flags: handle.flags,
+                        reuse_addr: handle.reuse_addr,
+                        max_seg: handle.max_seg
+                    })
+                } else {
+                    let mut parts = path.split("/");
+                    let remote = parse_socket(parts.next().unwrap_or(""));
+                    let mut local = parse_socket(parts.next().unwrap_or(""));
+
+                    // Take the first free ephemeral port after a random one, failing only when all are in use
+                    if local.1 == 0 {
+                        let start = self.rng.gen_range(32768, 65535);
+                        let ports = &self.ports;
+                        local.1 = (start .. 65535).chain(32768 .. start)
+                            .find(|port| ! ports.contains_key(port))
+                            .ok_or(Error::new(EADDRINUSE))?;
+                    }
+
+                    if local.1 <= 1024 && ! handle.privileged {
+                        return Err(Error::new(EACCES));
+                    }
+
+                    if self.ports.contains_key(&local.1) && ! handle.reuse_addr {
+                        return Err(Error::new(EADDRINUSE));
+                    }
+
+                    let mut new_handle = TcpHandle {
+                        uid: handle.uid,
+                        time_wait: self.time_wait,
+                        remote_mss: cmp::min(DEFAULT_MSS, handle.max_seg),
+                        max_seg: handle.max_seg,
+                        reuse_addr: handle.reuse_addr,
+                        ..TcpHandle::new(local, remote, handle.flags)
+                    };
+
+                    if new_handle.is_connected() {
+                        let time = monotonic()?;
+                        new_handle.snd_nxt = initial_sequence(self.isn_secret, new_handle.local, new_handle.remote, &time);
+                        new_handle.snd_una = new_handle.snd_nxt;
+                        new_handle.rcv_nxt = 0;
+                        new_handle.state = State::SynSent;
+                        new_handle.rto = new_handle.syn_rto;
+
+                        let tcp = new_handle.create_tcp(TCP_SYN, Vec::new());
+                        let ip = new_handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                        self.tcp_file.write_all(&ip.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+
+                        new_handle.push_unacked(TCP_SYN, Vec::new(), time);
+                        new_handle.advance_snd(1);
+                    }
+
+                    *self.ports.entry(new_handle.local.1).or_insert(0) += 1;
+
+                    Handle::Tcp(Box::new(new_handle))
+                }
+            },
+            Handle::Tcp(ref mut handle) => {
+                let mut new_handle = TcpHandle {
+                    uid: handle.uid,
+                    read_timeout: handle.read_timeout,
+                    write_timeout: handle.write_timeout,
+                    ttl: handle.ttl,
+                    state: handle.state,
+                    snd_nxt: handle.snd_nxt,
+                    snd_una: handle.snd_una,
+                    rcv_nxt: handle.rcv_nxt,
+                    send_buffer: handle.send_buffer,
+                    backlog: handle.backlog,
+                    syn_retries: handle.syn_retries,
+                    syn_rto: handle.syn_rto,
+                    max_retries: handle.max_retries,
+                    rto_max: handle.rto_max,
+                    time_wait: handle.time_wait,
+                    fin_wait2: handle.fin_wait2,
+                    linger: handle.linger,
+                    remote_mss: handle.remote_mss,
+                    max_seg: handle.max_seg,
+                    cwnd: handle.cwnd,
+                    ssthresh: handle.ssthresh,
+                    nodelay: handle.nodelay,
+                    ecn: handle.ecn,
+                    reuse_addr: handle.reuse_addr,
+                    keepalive: handle.keepalive,
+                    push_boundaries: handle.push_boundaries,
+                    keepalive_probes: handle.keepalive_probes,
+                    ecn_ok: handle.ecn_ok,
+                    ece: handle.ece,
+                    cwr: handle.cwr,
+                    ecn_recover: handle.ecn_recover,
+                    rcv_wscale: handle.rcv_wscale,
+                    snd_wscale: handle.snd_wscale,
+                    snd_wnd: handle.snd_wnd,
+                    max_snd_wnd: handle.max_snd_wnd,
+                    probes: handle.probes,
+                    bytes_sent: handle.bytes_sent,
+                    bytes_received: handle.bytes_received,
+                    retransmits: handle.retransmits,
+                    sack: handle.sack,
+                    recover: handle.recover,
+                    timestamps: handle.timestamps,
+                    ts_recent: handle.ts_recent,
+                    ts_recent_age: handle.ts_recent_age,
+                    reassembly: handle.reassembly.clone(),
+                    snd_up: handle.snd_up,
+                    oob: handle.oob,
+                    read_shutdown: handle.read_shutdown,
+                    snd_wl1: handle.snd_wl1,
+                    snd_wl2: handle.snd_wl2,
+                    ..TcpHandle::new(handle.local, handle.remote, handle.flags)
+                };
+
+                if path == "ttl" {
+                    Handle::Setting(file, SettingKind::Ttl)
+                } else if path == "read_timeout" {
+                    Handle::Setting(file, SettingKind::ReadTimeout)
+                } else if path == "write_timeout" {
+                    Handle::Setting(file, SettingKind::WriteTimeout)
+                } else if path == "syn_retries" {
+                    Handle::Setting(file, SettingKind::SynRetries)
+                } else if path == "syn_timeout" {
+                    Handle::Setting(file, SettingKind::SynTimeout)
+                } else if path == "retries" {
+                    Handle::Setting(file, SettingKind::Retries)
+                } else if path == "rto_max" {
+                    Handle::Setting(file, SettingKind::RtoMax)
+                } else if path == "rtt" {
+                    Handle::Setting(file, SettingKind::Rtt)
+                } else if path == "rto" {
+                    Handle::Setting(file, SettingKind::Rto)
+                } else if path == "nodelay" {
+                    Handle::Setting(file, SettingKind::NoDelay)
+                } else if path == "ecn" {
+                    Handle::Setting(file, SettingKind::Ecn)
+                } else if path == "keepalive" {
+                    Handle::Setting(file, SettingKind::KeepAlive)
+                } else if path == "push_boundaries" {
+                    Handle::Setting(file, SettingKind::PushBoundaries)
+                } else if path == "reuseaddr" {
+                    Handle::Setting(file, SettingKind::ReuseAddr)
+                } else if path == "time_wait" {
+                    Handle::Setting(file, SettingKind::TimeWait)
+                } else if path == "fin_wait2" {
+                    Handle::Setting(file, SettingKind::FinWait2)
+                } else if path == "linger" {
+                    Handle::Setting(file, SettingKind::Linger)
+                } else if path == "send_buffer" {
+                    Handle::Setting(file, SettingKind::SendBuffer)
+                } else if path == "backlog" {
+                    Handle::Setting(file, SettingKind::Backlog)
+                } else if path == "pending" {
+                    Handle::Setting(file, SettingKind::Pending)
+                } else if path == "stats" {
+                    Handle::Setting(file, SettingKind::Stats)
+                } else if path == "state" {
+                    Handle::Setting(file, SettingKind::State)
+                } else if path == "error" {
+                    Handle::Setting(file, SettingKind::Error)
+                } else if path == "mtu" {
+                    Handle::Setting(file, SettingKind::PathMtu)
+                } else if path == "maxseg" {
+                    Handle::Setting(file, SettingKind::MaxSeg)
+                } else if path == "shutdown" {
+                    Handle::Setting(file, SettingKind::Shutdown)
+                } else if path == "peek" {
+                    Handle::Setting(file, SettingKind::Peek)
+                } else if path == "oob" {
+                    Handle::Setting(file, SettingKind::Oob)
+                } else if path == "listen" {
+                    if handle.is_connected() {
+                        return Err(Error::new(EISCONN));
+                    } else if let Some((ip, tcp)) = handle.data.pop_front() {
+                        self.half_open -= 1;
+                        new_handle.remote = (ip.header.src, tcp.header.src.get());
+
+                        new_handle.rcv_nxt = tcp.header.sequence.get().wrapping_add(1);
+                        new_handle.remote_mss = cmp::min(send_mss(&tcp.options), new_handle.max_seg);
+                        new_handle.reset_cwnd();
+                        new_handle.rcv_wscale = parse_wscale(&tcp.options).map(|_| local_wscale());
+                        new_handle.snd_wscale = parse_wscale(&tcp.options).unwrap_or(0);
+                        new_handle.snd_wnd = tcp.header.window_size.get() as u32;
+                        new_handle.snd_wl1 = tcp.header.sequence.get();
+                        new_handle.max_snd_wnd = new_handle.snd_wnd;
+                        new_handle.sack = parse_sack_permitted(&tcp.options);
+                        new_handle.ecn_ok = tcp.header.flags.get() & (TCP_ECE | TCP_CWR) == TCP_ECE | TCP_CWR;
+                        new_handle.timestamps = parse_timestamps(&tcp.options).is_some();
+                        new_handle.ts_recent = parse_timestamps(&tcp.options).map_or(0, |(tsval, _)| tsval);
+
+                        if tcp.header.flags.get() & TCP_ACK == TCP_ACK {
+                            // The SYN-ACK was a cookie, and its ACK has already finished the handshake
+                            new_handle.state = State::Established;
+                            new_handle.snd_nxt = tcp.header.ack_num.get();
+                            new_handle.snd_una = new_handle.snd_nxt;
+                        } else {
+                            let time = monotonic()?;
+                            new_handle.state = State::SynReceived;
+                            new_handle.snd_nxt = initial_sequence(self.isn_secret, (ip.header.dst, tcp.header.dst.get()), new_handle.remote, &time);
+                            new_handle.snd_una = new_handle.snd_nxt;
+                            new_handle.rto = new_handle.syn_rto;
+
+                            new_handle.ts_recent_age = timestamp(&time);
+                            let tcp = new_handle.create_tcp(TCP_SYN | TCP_ACK, Vec::new());
+                            let ip = new_handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                            self.tcp_file.write_all(&ip.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+
+                            new_handle.push_unacked(TCP_SYN | TCP_ACK, Vec::new(), time);
+                            new_handle.advance_snd(1);
+                        }
+                    } else {
+                        return Err(Error::new(EWOULDBLOCK));
+                    }
+
+                    let queued = handle.data.len();
+                    handle.data.retain(|&(ref ip, ref tcp)| {
+                        if new_handle.matches(ip, tcp) {
+                            false
+                        } else {
+                            true
+                        }
+                    });
+                    self.half_open -= queued - handle.data.len();
+
+                    if let Some(port) = self.ports.get_mut(&handle.local.1) {
+                        *port = *port + 1;
+                    }
+
+                    Handle::Tcp(Box::new(new_handle))
+                } else if path.is_empty() {
+                    new_handle.data = handle.data.clone();
+
+                    if let Some(port) = self.ports.get_mut(&handle.local.1) {
+                        *port = *port + 1;
+                    }
+
+                    Handle::Tcp(Box::new(new_handle))
+                } else {
+                    return Err(Error::new(EINVAL));
+                }
+            },
+            Handle::Setting(file, kind) => {
+                Handle::Setting(file, kind)
+            },
+            Handle::Connections(_, _) => {
+                return Err(Error::new(EBADF));
+            }
+        })
+    }
+
+    /// One line for each connection the user may see, with its local and remote address and its state
+    fn list_connections(&self, privileged: bool, uid: u32) -> Vec<u8> {
+        let mut list = String::new();
+        for handle in self.handles.values() {
+            if let Handle::Tcp(ref handle) = *handle {
+                if privileged || handle.uid == uid {
+                    list.push_str(&format!("{}:{} {}:{} {}\n", handle.local.0.to_string(), handle.local.1, handle.remote.0.to_string(), handle.remote.1, handle.state.name()));
+                }
+            }
+        }
+        list.into_bytes()
+    }
+}
+
+impl SchemeMut for Tcpd {
+    fn open(&mut self, url: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let path = str::from_utf8(url).or(Err(Error::new(EINVAL)))?;
+
+        // Settings of an unconnected handle are dups of it. Opened directly they would refer to
+        // the placeholder below, which they replace
+        if path == "reuseaddr" || path == "time_wait" || path == "maxseg" {
+            return Err(Error::new(EINVAL));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.handles.insert(id, Handle::Empty(EmptyHandle {
+            privileged: uid == 0,
+            uid,
+            flags,
+            reuse_addr: false,
+            max_seg: local_mss()
+        }));
+
+        match self.inner_dup(id, path) {
+            Ok(handle) => {
+                self.insert_handle(id, handle);
+                Ok(id)
+            },
+            Err(err) => {
+                self.handles.remove(&id);
+                Err(err)
+            }
+        }
+    }
+
+    fn dup(&mut self, file: usize, buf: &[u8]) -> Result<usize> {
+        let path = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
+
+        let handle = self.inner_dup(file, path)?;
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.insert_handle(id, handle);
+
+        Ok(id)
+    }
+
+    fn read(&mut self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        let (file, kind) = match *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
+            Handle::Empty(ref _handle) => {
+                return Err(Error::new(EBADF));
+            },
+            Handle::Tcp(ref mut handle) => {
+                if ! handle.is_connected() {
+                    return Err(Error::new(ENOTCONN));
+                } else if let Some(err) = handle.error.take() {
+                    return Err(Error::new(err));
+                } else if ! handle.data.is_empty() {
+                    let window = handle.window();
+
+                    let len = handle.read_data(buf);
+
+                    handle.update_window();
+
+                    // Tell the peer once the application has drained enough to open the window
+                    if handle.window() > window && ! handle.read_closed() {
+                        handle.ack_pending = false;
+
+                        let tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                        let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                        self.tcp_file.write_all(&ip.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                    }
+
+                    return Ok(len);
+                } else if handle.flags & O_NONBLOCK == O_NONBLOCK && (handle.state == State::SynSent || handle.state == State::SynReceived) {
+                    // Not EOF, the connect has not finished yet
+                    return Err(Error::new(EINPROGRESS));
+                } else if handle.flags & O_NONBLOCK == O_NONBLOCK || handle.read_closed() {
+                    return Ok(0);
+                } else {
+                    return Err(Error::new(EWOULDBLOCK));
+                }
+            },
+            Handle::Setting(file, kind) => {
+                (file, kind)
+            },
+            Handle::Connections(ref list, ref mut offset) => {
+                let len = cmp::min(buf.len(), list.len() - *offset);
+                buf[.. len].copy_from_slice(&list[*offset .. *offset + len]);
+                *offset += len;
+                return Ok(len);
+            }
+        };
+
+        // An unbound handle has reuse and the MSS, and the TimeWait duration of every new connection
+        if let Handle::Empty(ref handle) = *self.handles.get(&file).ok_or(Error::new(EBADF))? {
+            return match kind {
+                SettingKind::MaxSeg => if buf.len() >= mem::size_of::<usize>() {
+                    let size = handle.max_seg as usize;
+                    let bytes = unsafe { slice::from_raw_parts(&size as *const usize as *const u8, mem::size_of::<usize>()) };
+                    buf[.. bytes.len()].copy_from_slice(bytes);
+                    Ok(bytes.len())
+                } else {
+                    Ok(0)
+                },
+                SettingKind::ReuseAddr => if let Some(reuse_addr) = buf.get_mut(0) {
+                    *reuse_addr = handle.reuse_addr as u8;
+                    Ok(1)
+                } else {
+                    Ok(0)
+                },
+                SettingKind::TimeWait => {
+                    millis(self.time_wait).deref().read(buf).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))
+                },
+                _ => Err(Error::new(EBADF))
+            };
+        }
+
+        if let Handle::Tcp(ref mut handle) = *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
+            let get_timeout = |timeout: &Option<TimeSpec>, buf: &mut [u8]| -> Result<usize> {
+                if let Some(ref timespec) = *timeout {
+                    timespec.deref().read(buf).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))
+                } else {
+                    Ok(0)
+                }
+            };
+
+            let get_size = |size: usize, buf: &mut [u8]| -> Result<usize> {
+                if buf.len() >= mem::size_of::<usize>() {
+                    let bytes = unsafe { slice::from_raw_parts(&size as *const usize as *const u8, mem::size_of::<usize>()) };
+                    buf[.. bytes.len()].copy_from_slice(bytes);
+                    Ok(bytes.len())
+                } else {
+                    Ok(0)
+                }
+            };
+
+            match kind {
+                SettingKind::Ttl => {
+                    if let Some(mut ttl) = buf.get_mut(0) {
+                        *ttl = handle.ttl;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::ReadTimeout => {
+                    get_timeout(&handle.read_timeout, buf)
+                },
+                SettingKind::WriteTimeout => {
+                    get_timeout(&handle.write_timeout, buf)
+                },
+                SettingKind::SynRetries => {
+                    if let Some(syn_retries) = buf.get_mut(0) {
+                        *syn_retries = handle.syn_retries;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::SynTimeout => {
+                    get_timeout(&Some(millis(handle.syn_rto)), buf)
+                },
+                SettingKind::Retries => {
+                    if let Some(retries) = buf.get_mut(0) {
+                        *retries = handle.max_retries;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::RtoMax => {
+                    get_timeout(&Some(millis(handle.rto_max)), buf)
+                },
+                SettingKind::Rtt => {
+                    get_timeout(&handle.srtt.map(|srtt| TimeSpec {
+                        tv_sec: (srtt / 1000000) as i64,
+                        tv_nsec: ((srtt % 1000000) * 1000) as i32
+                    }), buf)
+                },
+                SettingKind::Rto => {
+                    get_timeout(&Some(millis(handle.rto)), buf)
+                },
+                SettingKind::NoDelay => {
+                    if let Some(nodelay) = buf.get_mut(0) {
+                        *nodelay = handle.nodelay as u8;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::Ecn => {
+                    if let Some(ecn) = buf.get_mut(0) {
+                        *ecn = handle.ecn as u8;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::KeepAlive => {
+                    if let Some(keepalive) = buf.get_mut(0) {
+                        *keepalive = handle.keepalive as u8;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::PushBoundaries => {
+                    if let Some(push_boundaries) = buf.get_mut(0) {
+                        *push_boundaries = handle.push_boundaries as u8;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::ReuseAddr => {
+                    if let Some(reuse_addr) = buf.get_mut(0) {
+                        *reuse_addr = handle.reuse_addr as u8;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::TimeWait => {
+                    get_timeout(&Some(millis(handle.time_wait)), buf)
+                },
+                SettingKind::FinWait2 => {
+                    get_timeout(&Some(millis(handle.fin_wait2)), buf)
+                },
+                SettingKind::Linger => if buf.len() >= mem::size_of::<Linger>() {
+                    let bytes = unsafe { slice::from_raw_parts(&handle.linger as *const Linger as *const u8, mem::size_of::<Linger>()) };
+                    buf[.. bytes.len()].copy_from_slice(bytes);
+                    Ok(bytes.len())
+                } else {
+                    Ok(0)
+                },
+                SettingKind::SendBuffer => {
+                    get_size(handle.send_buffer, buf)
+                },
+                SettingKind::MaxSeg => {
+                    get_size(handle.remote_mss as usize, buf)
+                },
+                SettingKind::PathMtu => {
+                    get_size(handle.remote_mss as usize + mem::size_of::<Ipv4Header>() + mem::size_of::<TcpHeader>(), buf)
+                },
+                SettingKind::Backlog => {
+                    get_size(handle.backlog, buf)
+                },
+                SettingKind::Pending => {
+                    let pending = if handle.state == State::Listen {
+                        handle.data.len()
+                    } else {
+                        0
+                    };
+                    get_size(pending, buf)
+                },
+                SettingKind::Stats => {
+                    let srtt = match handle.srtt {
+                        Some(srtt) => format!("{}", srtt),
+                        None => "none".to_string()
+                    };
+
+                    let stats = format!(
+                        "state: {}\nbytes_sent: {}\nbytes_received: {}\nretransmits: {}\ncwnd: {}\nssthresh: {}\nsrtt_us: {}\nrto_ms: {}\nrcv_wnd: {}\nsnd_wnd: {}\n",
+                        handle.state.name(),
+                        handle.bytes_sent,
+                        handle.bytes_received,
+                        handle.retransmits,
+                        handle.cwnd,
+                        handle.ssthresh,
+                        srtt,
+                        handle.rto,
+                        handle.window(),
+                        handle.snd_wnd
+                    );
+
+                    let len = cmp::min(buf.len(), stats.len());
+                    buf[.. len].copy_from_slice(&stats.as_bytes()[.. len]);
+                    Ok(len)
+                },
+                // Taken from the connection at the time of the read
+                SettingKind::State => {
+                    let name = handle.state.name().as_bytes();
+                    let len = cmp::min(buf.len(), name.len());
+                    buf[.. len].copy_from_slice(&name[.. len]);
+                    Ok(len)
+                },
+                // Like SO_ERROR, reading the pending error clears it
+                SettingKind::Error => {
+                    let count = get_size(handle.error.unwrap_or(0) as usize, buf)?;
+                    if count > 0 {
+                        handle.error = None;
+                    }
+                    Ok(count)
+                },
+                SettingKind::Shutdown => {
+                    Err(Error::new(EINVAL))
+                },
+                SettingKind::Peek => {
+                    // Copy from the front of the stream without consuming it, so the next read
+                    // returns the same bytes
+                    if ! handle.is_connected() {
+                        return Err(Error::new(ENOTCONN));
+                    } else if let Some(err) = handle.error {
+                        return Err(Error::new(err));
+                    }
+
+                    let mut len = 0;
+                    for (_, tcp) in handle.data.iter() {
+                        let count = cmp::min(buf.len() - len, tcp.data.len());
+                        buf[len .. len + count].copy_from_slice(&tcp.data[.. count]);
+                        len += count;
+                        if len == buf.len() {
+                            break;
+                        }
+                    }
+                    Ok(len)
+                },
+                SettingKind::Oob => {
+                    match (handle.oob, buf.get_mut(0)) {
+                        (Some(oob), Some(byte)) => {
+                            *byte = oob;
+                            handle.oob = None;
+                            Ok(1)
+                        },
+                        _ => Ok(0)
+                    }
+                }
+            }
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn write(&mut self, file: usize, buf: &[u8]) -> Result<usize> {
+        let (file, kind) = match *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
+            Handle::Empty(ref _handle) => {
+                return Err(Error::new(EBADF));
+            },
+            Handle::Tcp(ref mut handle) => {
+                if ! handle.is_connected() {
+                    return Err(Error::new(ENOTCONN));
+                } else if let Some(err) = handle.error.take() {
+                    return Err(Error::new(err));
+                } else {
+                    match handle.state {
+                        // Only the peer has closed in CloseWait, so we can still send
+                        State::Established | State::CloseWait => {
+                            if handle.send_buf.len() >= handle.send_buffer {
+                                return Err(Error::new(EWOULDBLOCK));
+                            }
+
+                            let count = handle.buffer_data(buf);
+                            self.flush_now.insert(file);
+                            return Ok(count);
+                        },
+                        // Blocking writes wait in todo_write for the handshake to finish
+                        State::SynSent | State::SynReceived if handle.flags & O_NONBLOCK == O_NONBLOCK => {
+                            return Err(Error::new(EINPROGRESS));
+                        },
+                        State::Closed | State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck | State::TimeWait => {
+                            return Err(Error::new(EPIPE));
+                        },
+                        _ => {
+                            return Err(Error::new(EWOULDBLOCK));
+                        }
+                    }
+                }
+            },
+            Handle::Setting(file, kind) => {
+                (file, kind)
+            },
+            Handle::Connections(_, _) => {
+                return Err(Error::new(EBADF));
+            }
+        };
+
+        if let Handle::Empty(ref mut handle) = *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
+            return match kind {
+                SettingKind::MaxSeg => if buf.len() >= mem::size_of::<usize>() {
+                    let size = unsafe { ptr::read_unaligned(buf.as_ptr() as *const usize) };
+                    handle.max_seg = clamp_mss(size);
+                    Ok(mem::size_of::<usize>())
+                } else {
+                    handle.max_seg = local_mss();
+                    Ok(0)
+                },
+                SettingKind::ReuseAddr => if let Some(reuse_addr) = buf.first() {
+                    handle.reuse_addr = *reuse_addr != 0;
+                    Ok(1)
+                } else {
+                    Ok(0)
+                },
+                // Connections that already exist keep their own duration
+                SettingKind::TimeWait => if ! handle.privileged {
+                    Err(Error::new(EACCES))
+                } else if buf.len() >= mem::size_of::<TimeSpec>() {
+                    let mut timespec = TimeSpec::default();
+                    let count = timespec.deref_mut().write(buf).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                    self.time_wait = to_millis(&timespec);
+                    Ok(count)
+                } else {
+                    self.time_wait = TIME_WAIT;
+                    Ok(0)
+                },
+                _ => Err(Error::new(EBADF))
+            };
+        }
+
+        if let Handle::Tcp(ref mut handle) = *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
+            let set_timeout = |timeout: &mut Option<TimeSpec>, buf: &[u8]| -> Result<usize> {
+                if buf.len() >= mem::size_of::<TimeSpec>() {
+                    let mut timespec = TimeSpec::default();
+                    let count = timespec.deref_mut().write(buf).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                    *timeout = Some(timespec);
+                    Ok(count)
+                } else {
+                    *timeout = None;
+                    Ok(0)
+                }
+            };
+
+            let set_size = |size: &mut Option<usize>, buf: &[u8]| -> Result<usize> {
+                if buf.len() >= mem::size_of::<usize>() {
+                    *size = Some(unsafe { ptr::read_unaligned(buf.as_ptr() as *const usize) });
+                    Ok(mem::size_of::<usize>())
+                } else {
+                    *size = None;
+                    Ok(0)
+                }
+            };
+
+            match kind {
+                SettingKind::Ttl => {
+                    if let Some(ttl) = buf.get(0) {
+                        handle.ttl = *ttl;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::ReadTimeout => {
+                    set_timeout(&mut handle.read_timeout, buf)
+                },
+                SettingKind::WriteTimeout => {
+                    set_timeout(&mut handle.write_timeout, buf)
+                },
+                SettingKind::SynRetries => {
+                    if let Some(syn_retries) = buf.first() {
+                        handle.syn_retries = *syn_retries;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::SynTimeout => {
+                    let mut timeout = None;
+                    let count = set_timeout(&mut timeout, buf)?;
+                    handle.syn_rto = timeout.map_or(SYN_RTO_INITIAL, |timeout| to_millis(&timeout));
+
+                    // Apply to a connect that has not retransmitted yet
+                    if handle.state == State::SynSent && handle.retries == 0 {
+                        handle.rto = handle.syn_rto;
+                        self.timers.set(file, TimerKind::Retransmit, handle.retransmit_deadline());
+                    }
+
+                    Ok(count)
+                },
+                SettingKind::Retries => {
+                    if let Some(retries) = buf.first() {
+                        handle.max_retries = *retries;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::RtoMax => {
+                    let mut timeout = None;
+                    let count = set_timeout(&mut timeout, buf)?;
+                    handle.rto_max = timeout.map_or(RTO_MAX, |timeout| cmp::max(RTO_MIN, to_millis(&timeout)));
+                    handle.rto = cmp::min(handle.rto, handle.rto_max);
+                    Ok(count)
+                },
+                SettingKind::Rtt | SettingKind::Rto => {
+                    Err(Error::new(EINVAL))
+                },
+                SettingKind::NoDelay => {
+                    if let Some(nodelay) = buf.first() {
+                        handle.nodelay = *nodelay != 0;
+
+                        // Anything held back by Nagle's algorithm can go now
+                        if handle.nodelay && ! handle.send_buf.is_empty() {
+                            let time = monotonic()?;
+                            handle.output(false, time, &mut self.tcp_file, &mut self.rng).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                            self.timers.set(file, TimerKind::Retransmit, handle.retransmit_deadline());
+                        }
+
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::Ecn => {
+                    if let Some(ecn) = buf.first() {
+                        handle.ecn = *ecn != 0;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::KeepAlive => {
+                    if let Some(keepalive) = buf.first() {
+                        handle.keepalive = *keepalive != 0;
+                        handle.keepalive_probes = 0;
+
+                        if handle.keepalive {
+                            let time = monotonic()?;
+                            self.timers.arm(file, TimerKind::KeepAlive, add_time(&time, &millis(KEEPALIVE_IDLE)));
+                        } else {
+                            self.timers.cancel(file, TimerKind::KeepAlive);
+                        }
+
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::PushBoundaries => {
+                    if let Some(push_boundaries) = buf.first() {
+                        handle.push_boundaries = *push_boundaries != 0;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::ReuseAddr => {
+                    if let Some(reuse_addr) = buf.first() {
+                        handle.reuse_addr = *reuse_addr != 0;
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                },
+                SettingKind::TimeWait => {
+                    let mut timeout = None;
+                    let count = set_timeout(&mut timeout, buf)?;
+                    handle.time_wait = timeout.map_or(self.time_wait, |timeout| to_millis(&timeout));
+                    Ok(count)
+                },
+                SettingKind::FinWait2 => {
+                    let mut timeout = None;
+                    let count = set_timeout(&mut timeout, buf)?;
+                    handle.fin_wait2 = timeout.map_or(FIN_WAIT2, |timeout| to_millis(&timeout));
+                    Ok(count)
+                },
+                // An empty write turns lingering off
+                SettingKind::Linger => if buf.len() >= mem::size_of::<Linger>() {
+                    handle.linger = unsafe { ptr::read_unaligned(buf.as_ptr() as *const Linger) };
+                    Ok(mem::size_of::<Linger>())
+                } else {
+                    handle.linger = Linger::default();
+                    Ok(0)
+                },
+                // Takes effect on the next SYN, and lowers the MSS of a connection already made
+                SettingKind::MaxSeg => {
+                    let mut size = None;
+                    let count = set_size(&mut size, buf)?;
+                    handle.max_seg = size.map_or(local_mss(), clamp_mss);
+                    handle.reduce_mss(handle.max_seg);
+                    Ok(count)
+                },
+                // Lowered when the path cannot carry our segments, as a fragmentation needed message reports
+                SettingKind::PathMtu => {
+                    let mut size = None;
+                    let count = set_size(&mut size, buf)?;
+                    if let Some(mtu) = size {
+                        if mtu < MTU_MIN {
+                            return Err(Error::new(EINVAL));
+                        }
+
+                        let mss = mtu - mem::size_of::<Ipv4Header>() - mem::size_of::<TcpHeader>();
+                        handle.reduce_mss(cmp::min(mss, u16::MAX as usize) as u16);
+
+                        // The oldest segment is the one that was too large, so it goes again at once
+                        // without counting as a timeout
+                        let time = monotonic()?;
+                        let resend = handle.unacked.front().map(|segment| (segment.seq, segment.flags, segment.data.clone()));
+                        if let Some((seq, flags, data)) = resend {
+                            let mut tcp = handle.create_tcp(flags, data);
+                            tcp.header.sequence.set(seq);
+                            handle.set_urgent(&mut tcp);
+                            let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                            self.tcp_file.write_all(&ip.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+
+                            if let Some(segment) = handle.unacked.front_mut() {
+                                segment.sent = time;
+                                segment.retransmitted = true;
+                            }
+                        }
+                        self.timers.set(file, TimerKind::Retransmit, handle.retransmit_deadline());
+                    }
+                    Ok(count)
+                },
+                SettingKind::SendBuffer => {
+                    let mut size = None;
+                    let count = set_size(&mut size, buf)?;
+                    // Always room for at least one full segment
+                    handle.send_buffer = size.map_or(SEND_BUFFER, |size| cmp::max(size, local_mss() as usize));
+                    Ok(count)
+                },
+                SettingKind::Backlog => {
+                    let mut size = None;
+                    let count = set_size(&mut size, buf)?;
+                    handle.backlog = size.map_or(BACKLOG, |size| cmp::max(size, 1));
+                    Ok(count)
+                },
+                SettingKind::Pending | SettingKind::Stats | SettingKind::State | SettingKind::Error | SettingKind::Peek => {
+                    Err(Error::new(EINVAL))
+                },
+                // Data written here is sent as part of the stream, with the urgent pointer after its last byte
+                SettingKind::Oob => {
+                    match handle.state {
+                        State::Established | State::CloseWait => {
+                            let count = handle.buffer_data(buf);
+                            if count > 0 {
+                                handle.snd_up = Some(handle.snd_nxt.wrapping_add(handle.send_buf.len() as u32));
+                                self.flush_now.insert(file);
+                            }
+                            Ok(count)
+                        },
+                        State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck | State::TimeWait | State::Closed => {
+                            Err(Error::new(EPIPE))
+                        },
+                        _ => {
+                            Err(Error::new(ENOTCONN))
+                        }
+                    }
+                },
+                SettingKind::Shutdown => {
+                    if let Some(&how) = buf.first() {
+                        if how != SHUT_RD && how != SHUT_WR && how != SHUT_RDWR {
+                            return Err(Error::new(EINVAL));
+                        } else if ! handle.is_connected() || handle.state == State::Closed {
+                            return Err(Error::new(ENOTCONN));
+                        }
+
+                        if how != SHUT_WR && ! handle.read_shutdown {
+                            handle.read_shutdown = true;
+
+                            // Unread data is dropped and blocked reads see the end of the stream
+                            handle.data.clear();
+                            handle.update_window();
+                            while let Some((_timeout, mut packet)) = handle.todo_read.pop_front() {
+                                packet.a = 0;
+                                self.scheme_file.write_all(&packet).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                            }
+                        }
+
+                        if how != SHUT_RD {
+                            let next = match handle.state {
+                                State::SynReceived | State::Established => Some(State::FinWait1),
+                                State::CloseWait => Some(State::LastAck),
+                                _ => None
+                            };
+
+                            if let Some(next) = next {
+                                self.half_open -= handle.half_open();
+                                handle.state = next;
+
+                                let time = monotonic()?;
+                                handle.send_fin(time, &mut self.tcp_file, &mut self.rng).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                                self.timers.set(file, TimerKind::Retransmit, handle.retransmit_deadline());
+
+                                while let Some((_timeout, mut packet)) = handle.todo_write.pop_front() {
+                                    packet.a = (-EPIPE) as usize;
+                                    self.scheme_file.write_all(&packet).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                                }
+                            }
+                        }
+
+                        Ok(1)
+                    } else {
+                        Ok(0)
+                    }
+                }
+            }
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn fcntl(&mut self, file: usize, cmd: usize, arg: usize) -> Result<usize> {
+        match *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
+            Handle::Empty(ref mut handle) => {
+                match cmd {
+                    F_GETFL => Ok(handle.flags),
+                    F_SETFL => {
+                        handle.flags = arg & ! O_ACCMODE;
+                        Ok(0)
+                    },
+                    _ => Err(Error::new(EINVAL))
+                }
+            },
+            Handle::Tcp(ref mut handle) => {
+                match cmd {
+                    F_GETFL => Ok(handle.flags),
+                    F_SETFL => {
+                        handle.flags = arg & ! O_ACCMODE;
+                        Ok(0)
+                    },
+                    _ => Err(Error::new(EINVAL))
+                }
+            }
+            _ => Err(Error::new(EBADF))
+        }
+    }
+
+    fn fevent(&mut self, file: usize, flags: usize) -> Result<usize> {
+        if let Handle::Tcp(ref mut handle) = *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
+            handle.events = flags;
+            Ok(file)
+        } else {
+            Err(Error::new(EBADF))
+        }
+    }
+
+    fn fpath(&mut self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        if let Handle::Tcp(ref mut handle) = *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
+            let path_string = format!("tcp:{}:{}/{}:{}", handle.remote.0.to_string(), handle.remote.1, handle.local.0.to_string(), handle.local.1);
+            let path = path_string.as_bytes();
+
+            let mut i = 0;
+            while i < buf.len() && i < path.len() {
+                buf[i] = path[i];
+                i += 1;
+            }
+
+            Ok(i)
+        } else {
             Err(Error::new(EBADF))
         }
     }
 
-    fn write(&mut self, file: usize, buf: &[u8]) -> Result<usize> {
-        let (file, kind) = match *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
-            Handle::Empty(ref _handle) => {
-                return Err(Error::new(EBADF));
-            },
-            Handle::Tcp(ref mut handle) => {
-                if ! handle.is_connected() {
-                    return Err(Error::new(ENOTCONN));
-                } else if buf.len() >= 65507 {
-                    return Err(Error::new(EMSGSIZE));
-                } else {
-                    match handle.state {
-                        State::Established => {
-                            let tcp = handle.create_tcp(TCP_ACK | TCP_PSH, buf.to_vec());
-                            let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                            self.tcp_file.write(&ip.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
-                            handle.seq += buf.len() as u32;
-                            return Ok(buf.len());
-                        },
-                        _ => {
-                            return Err(Error::new(EWOULDBLOCK));
-                        }
-                    }
-                }
-            },
-            Handle::Setting(file, kind) => {
-                (file, kind)
-            }
+    /// Wait for everything written so far to be acknowledged by the peer
+    fn fsync(&mut self, file: usize) -> Result<usize> {
+        match *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
+            // What a failed connection did not get acknowledged never will be, which is reported
+            // with the error that ended it if nothing has taken that yet
+            Handle::Tcp(ref mut handle) if handle.state == State::Closed && ! handle.synced() => {
+                Err(Error::new(handle.error.take().unwrap_or(EPIPE)))
+            },
+            Handle::Tcp(ref handle) if ! handle.synced() => Err(Error::new(EWOULDBLOCK)),
+            _ => Ok(0)
+        }
+    }
+
+    fn close(&mut self, file: usize) -> Result<usize> {
+        let closed = {
+            if let Handle::Tcp(ref mut handle) = *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
+                // Closing with received data unread tells the peer it was lost (RFC 1122 section
+                // 4.2.2.13), where a FIN would claim it was delivered
+                let unread = handle.state != State::Listen && (! handle.data.is_empty() || ! handle.reassembly.is_empty());
+
+                // Nothing will accept what a listener has queued. Its clients are reset instead of
+                // being left to retry, and anyone blocked accepting is told the listener is gone
+                if handle.state == State::Listen {
+                    for (ip, tcp) in handle.data.iter() {
+                        let ip = create_reset(ip, tcp, self.rng.gen());
+                        self.tcp_file.write_all(&ip.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                    }
+
+                    while let Some(mut packet) = handle.todo_dup.pop_front() {
+                        packet.a = (-ECONNABORTED) as usize;
+                        self.scheme_file.write_all(&packet).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                    }
+
+                    self.half_open -= handle.half_open();
+                }
+
+                handle.data.clear();
+
+                // Nobody is left to read, but the peer may keep sending until it closes its side.
+                // Its data is still acknowledged and then discarded, so the window stays open
+                handle.read_shutdown = true;
+                handle.closed = true;
+
+                if unread || (handle.linger.enabled != 0 && handle.linger.seconds == 0) {
+                    // Unread data or a zero linger time abort the connection, skipping TimeWait
+                    match handle.state {
+                        State::Listen | State::SynSent | State::TimeWait | State::Closed => (),
+                        _ => {
+                            let tcp = handle.create_tcp(TCP_RST | TCP_ACK, Vec::new());
+                            let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                            self.tcp_file.write_all(&ip.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                        }
+                    }
+
+                    true
+                } else {
+                    let closed = match handle.state {
+                        State::SynReceived | State::Established | State::CloseWait => {
+                            self.half_open -= handle.half_open();
+                            handle.state = if handle.state == State::CloseWait {
+                                State::LastAck
+                            } else {
+                                State::FinWait1
+                            };
+
+                            let time = monotonic()?;
+                            handle.send_fin(time, &mut self.tcp_file, &mut self.rng).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                            self.timers.set(file, TimerKind::Retransmit, handle.retransmit_deadline());
+
+                            // Nothing more can be sent after the FIN
+                            while let Some((_timeout, mut packet)) = handle.todo_write.pop_front() {
+                                packet.a = (-EPIPE) as usize;
+                                self.scheme_file.write_all(&packet).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                            }
+
+                            false
+                        },
+                        // Writing was already shut down, so the FIN is on its way
+                        State::FinWait1 | State::Closing | State::LastAck | State::TimeWait => false,
+                        State::FinWait2 => {
+                            let time = monotonic()?;
+                            self.timers.arm(file, TimerKind::FinWait2, add_time(&time, &millis(handle.fin_wait2)));
+
+                            false
+                        },
+                        _ => true
+                    };
+
+                    // With a linger time, wait for the peer to acknowledge everything or reset it
+                    if handle.linger.enabled != 0 && ! closed && handle.snd_una != handle.snd_nxt {
+                        let time = monotonic()?;
+                        self.timers.arm(file, TimerKind::Linger, add_time(&time, &millis(handle.linger.seconds as u64 * 1000)));
+
+                        if handle.flags & O_NONBLOCK != O_NONBLOCK {
+                            return Err(Error::new(EWOULDBLOCK));
+                        }
+                    }
+
+                    closed
+                }
+            } else {
+                true
+            }
+        };
+
+        if closed {
+            self.remove_handle(file).ok_or(Error::new(EBADF))?;
+        }
+
+        Ok(0)
+    }
+}
+
+fn daemon(scheme_fd: usize, tcp_fd: usize, time_fd: usize) {
+    let scheme_file = unsafe { File::from_raw_fd(scheme_fd) };
+    let tcp_file = unsafe { File::from_raw_fd(tcp_fd) };
+    let time_file = unsafe { File::from_raw_fd(time_fd) };
+
+    let tcpd = Rc::new(RefCell::new(Tcpd::new(scheme_file, tcp_file, time_file)));
+
+    let mut event_queue = EventQueue::<()>::new().expect("tcpd: failed to create event queue");
+
+    let time_tcpd = tcpd.clone();
+    event_queue.add(time_fd, move |_count: usize| -> io::Result<Option<()>> {
+        time_tcpd.borrow_mut().time_event()?;
+        Ok(None)
+    }).expect("tcpd: failed to listen to events on time:");
+
+    let tcp_tcpd = tcpd.clone();
+    event_queue.add(tcp_fd, move |_count: usize| -> io::Result<Option<()>> {
+        tcp_tcpd.borrow_mut().tcp_event()?;
+        Ok(None)
+    }).expect("tcpd: failed to listen to events on ip:6");
+
+    event_queue.add(scheme_fd, move |_count: usize| -> io::Result<Option<()>> {
+        tcpd.borrow_mut().scheme_event()?;
+        Ok(None)
+    }).expect("tcpd: failed to listen to events on :tcp");
+
+    event_queue.trigger_all(0).expect("tcpd: failed to trigger event queue");
+
+    event_queue.run().expect("tcpd: failed to run event queue");
+}
+
+fn main() {
+    let time_path = format!("time:{}", CLOCK_MONOTONIC);
+    match syscall::open(&time_path, O_RDWR) {
+        Ok(time_fd) => {
+            println!("tcpd: opening ip:6");
+            match syscall::open("ip:6", O_RDWR | O_NONBLOCK) {
+                Ok(tcp_fd) => {
+                    // Daemonize
+                    if unsafe { syscall::clone(0).unwrap() } == 0 {
+                        println!("tcpd: providing tcp:");
+                        match syscall::open(":tcp", O_RDWR | O_CREAT | O_NONBLOCK) {
+                            Ok(scheme_fd) => {
+                                daemon(scheme_fd, tcp_fd, time_fd);
+                            },
+                            Err(err) => {
+                                println!("tcpd: failed to create tcp scheme: {}", err);
+                                process::exit(1);
+                            }
+                        }
+                    }
+                },
+                Err(err) => {
+                    println!("tcpd: failed to open ip:6: {}", err);
+                    process::exit(1);
+                }
+            }
+        },
+        Err(err) => {
+            println!("tcpd: failed to open {}: {}", time_path, err);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+
+    /// An anonymous file standing in for one of the schemes
+    fn temp_file() -> File {
+        let path = env::temp_dir().join(format!("tcpd-test-{}-{}", process::id(), FILES.fetch_add(1, Ordering::SeqCst)));
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        file
+    }
+
+    /// Take everything written to a file since the last call
+    fn take(file: &mut File) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut bytes).unwrap();
+        file.set_len(0).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        bytes
+    }
+
+    fn tcpd() -> Tcpd {
+        Tcpd::new(temp_file(), temp_file(), temp_file())
+    }
+
+    /// An application's write, sent as scheme_event would send it at the end of a batch
+    fn write(tcpd: &mut Tcpd, id: usize, buf: &[u8]) -> Result<usize> {
+        let result = tcpd.write(id, buf);
+        tcpd.flush().unwrap();
+        result
+    }
+
+    /// The packets a daemon has sent since the last call
+    fn sent_ip(tcpd: &mut Tcpd) -> Vec<(Ipv4, Tcp)> {
+        let bytes = take(&mut tcpd.tcp_file);
+        let mut packets = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let len = (bytes[i + 2] as usize) << 8 | bytes[i + 3] as usize;
+            let ip = Ipv4::from_bytes(&bytes[i .. i + len]).unwrap();
+            let tcp = Tcp::from_bytes(&ip.data).unwrap();
+            packets.push((ip, tcp));
+            i += len;
+        }
+        packets
+    }
+
+    /// The segments a daemon has sent since the last call
+    fn sent(tcpd: &mut Tcpd) -> Vec<Tcp> {
+        sent_ip(tcpd).into_iter().map(|(_ip, tcp)| tcp).collect()
+    }
+
+    /// The answers a daemon has written to the scheme since the last call
+    fn answers(tcpd: &mut Tcpd) -> Vec<Packet> {
+        take(&mut tcpd.scheme_file).chunks(mem::size_of::<Packet>()).map(|bytes| {
+            let mut packet = Packet::default();
+            packet.copy_from_slice(bytes);
+            packet
+        }).collect()
+    }
+
+    /// Run the timers as if the clock read `time`
+    fn at(tcpd: &mut Tcpd, time: TimeSpec) {
+        take(&mut tcpd.time_file);
+        tcpd.time_file.write_all(&time).unwrap();
+        tcpd.time_file.seek(SeekFrom::Start(0)).unwrap();
+        tcpd.time_event().unwrap();
+    }
+
+    /// A connection from 10.0.0.1:5000 to 10.0.0.2:80 that has sent its SYN
+    fn connect(tcpd: &mut Tcpd) -> usize {
+        let id = tcpd.open(b"10.0.0.2:80/10.0.0.1:5000", O_RDWR, 0, 0).ok().unwrap();
+        sent(tcpd);
+        id
+    }
+
+    /// Hand a segment to the daemon as if it had been read from the ip: scheme. The IP
+    /// payload and both checksums are filled in from the TCP segment
+    fn deliver(tcpd: &mut Tcpd, segment: (Ipv4, Tcp)) {
+        let (mut ip, mut tcp) = segment;
+        let proto = n16::new(0x06);
+        let segment_len = n16::new(tcp.to_bytes().len() as u16);
+        tcp.header.checksum.data = 0;
+        let bytes = tcp.to_bytes();
+        tcp.header.checksum.data = Checksum::compile(unsafe {
+            Checksum::sum(ip.header.src.bytes.as_ptr() as usize, ip.header.src.bytes.len()) +
+            Checksum::sum(ip.header.dst.bytes.as_ptr() as usize, ip.header.dst.bytes.len()) +
+            Checksum::sum((&proto as *const n16) as usize, mem::size_of::<n16>()) +
+            Checksum::sum((&segment_len as *const n16) as usize, mem::size_of::<n16>()) +
+            Checksum::sum(bytes.as_ptr() as usize, bytes.len())
+        });
+        ip.data = tcp.to_bytes();
+        ip.header.len = n16::new((ip.data.len() + mem::size_of::<Ipv4Header>()) as u16);
+        ip.header.checksum.data = 0;
+        ip.header.checksum.data = Checksum::compile(unsafe {
+            Checksum::sum((&ip.header as *const Ipv4Header) as usize, mem::size_of::<Ipv4Header>())
+        });
+
+        let pending = take(&mut tcpd.tcp_file);
+        let bytes = ip.to_bytes();
+        tcpd.tcp_file.write_all(&bytes).unwrap();
+        tcpd.tcp_file.seek(SeekFrom::Start(0)).unwrap();
+        tcpd.tcp_event().unwrap();
+
+        // The answers were written after the segment, keep them for `sent`
+        let written = take(&mut tcpd.tcp_file);
+        tcpd.tcp_file.write_all(&pending).unwrap();
+        tcpd.tcp_file.write_all(&written[bytes.len() ..]).unwrap();
+    }
+
+    /// A segment from the peer of `connect`
+    fn from_peer(flags: u16, seq: u32, ack: u32, data: &[u8]) -> (Ipv4, Tcp) {
+        let tcp = Tcp {
+            header: TcpHeader {
+                src: n16::new(80),
+                dst: n16::new(5000),
+                sequence: n32::new(seq),
+                ack_num: n32::new(ack),
+                flags: n16::new(((mem::size_of::<TcpHeader>() << 10) & 0xF000) as u16 | flags),
+                window_size: n16::new(65535),
+                checksum: Checksum { data: 0 },
+                urgent_pointer: n16::new(0),
+            },
+            options: Vec::new(),
+            data: data.to_vec()
+        };
+
+        let ip = Ipv4 {
+            header: Ipv4Header {
+                ver_hlen: 0x45,
+                services: 0,
+                len: n16::new((tcp.to_bytes().len() + mem::size_of::<Ipv4Header>()) as u16),
+                id: n16::new(0),
+                flags_fragment: n16::new(0),
+                ttl: 64,
+                proto: 0x06,
+                checksum: Checksum { data: 0 },
+                src: Ipv4Addr::from_str("10.0.0.2"),
+                dst: Ipv4Addr::from_str("10.0.0.1")
+            },
+            options: Vec::new(),
+            data: tcp.to_bytes()
+        };
+
+        (ip, tcp)
+    }
+
+    /// Replace the options of a segment, adjusting its data offset
+    fn with_options(segment: (Ipv4, Tcp), options: &[u8]) -> (Ipv4, Tcp) {
+        let (ip, mut tcp) = segment;
+        let flags = tcp.header.flags.get() & 0xFFF;
+        tcp.header.flags = n16::new((((mem::size_of::<TcpHeader>() + options.len()) << 10) & 0xF000) as u16 | flags);
+        tcp.options = options.to_vec();
+        (ip, tcp)
+    }
+
+    /// Initial sequence number of the peer of `established`
+    const PEER_ISN: u32 = 0x1000;
+
+    /// A connection that has finished its handshake with a peer that offered no options
+    fn established(tcpd: &mut Tcpd) -> usize {
+        established_with(tcpd, &[])
+    }
+
+    /// A connection that has finished its handshake with a peer that offered `options`
+    fn established_with(tcpd: &mut Tcpd, options: &[u8]) -> usize {
+        let id = connect(tcpd);
+        let seq = tcp_handle(tcpd, id).snd_nxt;
+
+        deliver(tcpd, with_options(from_peer(TCP_SYN | TCP_ACK, PEER_ISN, seq, &[]), options));
+        assert_eq!(tcp_handle(tcpd, id).state, State::Established);
+        sent(tcpd);
+        id
+    }
+
+    /// Send an `established` connection data until its receive window closes, without reading
+    /// any of it. Returns the peer's next sequence number
+    fn fill_window(tcpd: &mut Tcpd, id: usize) -> u32 {
+        let ack = tcp_handle(tcpd, id).snd_nxt;
+        let mut seq = PEER_ISN + 1;
+        while tcp_handle(tcpd, id).window() > 0 {
+            let len = cmp::min(tcp_handle(tcpd, id).window() as usize, 32768);
+            deliver(tcpd, from_peer(TCP_ACK, seq, ack, &vec![0; len]));
+            seq = seq.wrapping_add(len as u32);
+        }
+        sent(tcpd);
+        seq
+    }
+
+    fn tcp_handle(tcpd: &mut Tcpd, id: usize) -> &mut TcpHandle {
+        match tcpd.handles.get_mut(&id) {
+            Some(&mut Handle::Tcp(ref mut handle)) => handle,
+            _ => panic!("handle {} is not a connection", id)
+        }
+    }
+
+    #[test]
+    fn seq_wraparound() {
+        assert!(seq_lt(0xFFFF_FFFF, 0));
+        assert!(seq_lt(0xFFFF_FF00, 0x10));
+        assert!(! seq_lt(0, 0xFFFF_FFFF));
+        assert!(! seq_lt(5, 5));
+
+        assert!(seq_leq(0xFFFF_FFFF, 0));
+        assert!(seq_leq(0, 0));
+        assert!(seq_leq(0xFFFF_FFFF, 0xFFFF_FFFF));
+        assert!(! seq_leq(0, 0xFFFF_FFFF));
+
+        assert!(seq_gt(0, 0xFFFF_FFFF));
+        assert!(seq_gt(0x10, 0xFFFF_FF00));
+        assert!(! seq_gt(0xFFFF_FFFF, 0));
+        assert!(! seq_gt(5, 5));
+    }
+
+    #[test]
+    fn seq_half_space() {
+        // Up to 2^31 - 1 apart the order is clear in both directions
+        assert!(seq_lt(0, 0x7FFF_FFFF));
+        assert!(seq_gt(0x7FFF_FFFF, 0));
+        assert!(seq_lt(0x8000_0000, 0xFFFF_FFFF));
+        assert!(seq_gt(0, 0x8000_0001));
+
+        // Exactly 2^31 apart each comes before the other, and neither after
+        assert!(seq_lt(0, 0x8000_0000));
+        assert!(seq_lt(0x8000_0000, 0));
+        assert!(seq_leq(0, 0x8000_0000));
+        assert!(seq_leq(0x8000_0000, 0));
+        assert!(! seq_gt(0, 0x8000_0000));
+        assert!(! seq_gt(0x8000_0000, 0));
+    }
+
+    #[test]
+    fn seq_in_window_wraparound() {
+        assert!(seq_in_window(0xFFFF_FFF0, 0xFFFF_FFF0, 0x20));
+        assert!(seq_in_window(0xFFFF_FFFF, 0xFFFF_FFF0, 0x20));
+        assert!(seq_in_window(0, 0xFFFF_FFF0, 0x20));
+        assert!(seq_in_window(0xF, 0xFFFF_FFF0, 0x20));
+        assert!(! seq_in_window(0x10, 0xFFFF_FFF0, 0x20));
+        assert!(! seq_in_window(0xFFFF_FFEF, 0xFFFF_FFF0, 0x20));
+
+        // An empty window holds nothing, not even its start
+        assert!(! seq_in_window(0, 0, 0));
+
+        // The largest unambiguous window
+        assert!(seq_in_window(0x7FFF_FFFE, 0xFFFF_FFFF, 0x8000_0000));
+        assert!(! seq_in_window(0x7FFF_FFFF, 0xFFFF_FFFF, 0x8000_0000));
+    }
+
+    #[test]
+    fn syn_black_hole() {
+        let mut tcpd = tcpd();
+        let id = connect(&mut tcpd);
+        tcp_handle(&mut tcpd, id).todo_read.push_back((None, Packet::default()));
+        let start = monotonic().ok().unwrap();
+
+        // Each unanswered SYN doubles the wait for the next one
+        let mut elapsed = 0;
+        for backoff in &[3000, 6000, 12000, 24000, 48000] {
+            elapsed += backoff;
+            at(&mut tcpd, add_time(&start, &millis(elapsed - 1)));
+            assert!(sent(&mut tcpd).is_empty());
+            at(&mut tcpd, add_time(&start, &millis(elapsed)));
+            let segments = sent(&mut tcpd);
+            assert_eq!(segments.len(), 1);
+            assert_eq!(segments[0].header.flags.get() & (TCP_SYN | TCP_ACK), TCP_SYN);
+        }
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::SynSent);
+
+        // The last one goes unanswered too and the connect fails, after a wait capped at RTO_MAX
+        at(&mut tcpd, add_time(&start, &millis(elapsed + RTO_MAX - 1)));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::SynSent);
+        at(&mut tcpd, add_time(&start, &millis(elapsed + RTO_MAX)));
+        assert!(sent(&mut tcpd).is_empty());
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Closed);
+        let answers = answers(&mut tcpd);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].a, (-ETIMEDOUT) as usize);
+    }
+
+    #[test]
+    fn fin_lost() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+
+        tcpd.close(id).unwrap();
+        let start = monotonic().ok().unwrap();
+        let fin = sent(&mut tcpd);
+        assert_eq!(fin.len(), 1);
+        assert_eq!(fin[0].header.flags.get() & TCP_FIN, TCP_FIN);
+        let fin_seq = fin[0].header.sequence.get();
+
+        // The FIN is lost, so it goes out again with the sequence number it already used
+        at(&mut tcpd, add_time(&start, &millis(RTO_INITIAL)));
+        let retransmit = sent(&mut tcpd);
+        assert_eq!(retransmit.len(), 1);
+        assert_eq!(retransmit[0].header.flags.get() & TCP_FIN, TCP_FIN);
+        assert_eq!(retransmit[0].header.sequence.get(), fin_seq);
+        assert_eq!(tcp_handle(&mut tcpd, id).snd_nxt, fin_seq.wrapping_add(1));
+
+        // This one arrives and the peer closes its side as well
+        deliver(&mut tcpd, from_peer(TCP_FIN | TCP_ACK, PEER_ISN + 1, fin_seq.wrapping_add(1), &[]));
+        let ack = sent(&mut tcpd);
+        assert_eq!(ack.len(), 1);
+        assert_eq!(ack[0].header.flags.get() & (TCP_FIN | TCP_ACK), TCP_ACK);
+        assert_eq!(ack[0].header.ack_num.get(), PEER_ISN + 2);
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::TimeWait);
+    }
+
+    #[test]
+    fn fin_wait2_timeout() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+
+        tcpd.close(id).unwrap();
+        let fin_seq = sent(&mut tcpd)[0].header.sequence.get();
+
+        // The peer acknowledges our FIN but never sends its own
+        let before = monotonic().ok().unwrap();
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, fin_seq.wrapping_add(1), &[]));
+        let after = monotonic().ok().unwrap();
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::FinWait2);
+        assert!(sent(&mut tcpd).is_empty());
+
+        at(&mut tcpd, add_time(&before, &millis(FIN_WAIT2 - 1)));
+        assert!(tcpd.handles.contains_key(&id));
+
+        at(&mut tcpd, add_time(&after, &millis(FIN_WAIT2)));
+        assert!(! tcpd.handles.contains_key(&id));
+        assert!(! tcpd.ports.contains_key(&5000));
+        let reset = sent(&mut tcpd);
+        assert_eq!(reset.len(), 1);
+        assert_eq!(reset[0].header.flags.get() & TCP_RST, TCP_RST);
+    }
+
+    #[test]
+    fn window_from_reordered_ack() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        assert_eq!(write(&mut tcpd, id, &[0; 200]).ok(), Some(200));
+        assert_eq!(sent(&mut tcpd).len(), 1);
+
+        // The peer acknowledges the data in two steps, but the second ACK overtakes the first
+        let mut second = from_peer(TCP_ACK, PEER_ISN + 1, start.wrapping_add(200), &[]);
+        second.1.header.window_size = n16::new(30000);
+        deliver(&mut tcpd, second);
+        assert_eq!(tcp_handle(&mut tcpd, id).snd_wnd, 30000);
+
+        let mut first = from_peer(TCP_ACK, PEER_ISN + 1, start.wrapping_add(100), &[]);
+        first.1.header.window_size = n16::new(100);
+        deliver(&mut tcpd, first);
+        assert_eq!(tcp_handle(&mut tcpd, id).snd_wnd, 30000);
+
+        // A later window update still counts, even if it shrinks the window
+        let mut update = from_peer(TCP_ACK, PEER_ISN + 1, start.wrapping_add(200), &[]);
+        update.1.header.window_size = n16::new(1000);
+        deliver(&mut tcpd, update);
+        assert_eq!(tcp_handle(&mut tcpd, id).snd_wnd, 1000);
+    }
+
+    #[test]
+    fn partial_acks() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+        let mss = DEFAULT_MSS as u32;
+
+        assert_eq!(write(&mut tcpd, id, &[0; 3 * DEFAULT_MSS as usize]).ok(), Some(3 * DEFAULT_MSS as usize));
+        assert_eq!(sent(&mut tcpd).len(), INITIAL_CWND as usize);
+
+        for i in 1 .. 3 {
+            // Each ACK covers one more segment and restarts the timer for the oldest one left.
+            // The first also opens the congestion window for the third segment
+            deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, start.wrapping_add(i * mss), &[]));
+            sent(&mut tcpd);
+            let deadline = {
+                let handle = tcp_handle(&mut tcpd, id);
+                assert_eq!(handle.snd_una, start.wrapping_add(i * mss));
+                assert_eq!(handle.unacked.len(), 3 - i as usize);
+                assert_eq!(handle.unacked.front().unwrap().seq, start.wrapping_add(i * mss));
+                assert_eq!(handle.retries, 0);
+                handle.retransmit_deadline().unwrap()
+            };
+
+            at(&mut tcpd, deadline);
+            let resent = sent(&mut tcpd);
+            assert_eq!(resent.len(), 1);
+            assert_eq!(resent[0].header.sequence.get(), start.wrapping_add(i * mss));
+        }
+
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, start.wrapping_add(3 * mss), &[]));
+        assert!(tcp_handle(&mut tcpd, id).unacked.is_empty());
+        assert!(tcp_handle(&mut tcpd, id).retransmit_deadline().is_none());
+    }
+
+    #[test]
+    fn window_probe() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        let seq = fill_window(&mut tcpd, id);
+        let segments = tcp_handle(&mut tcpd, id).data.len();
+
+        // A one byte probe is not taken, but answered with the closed window
+        deliver(&mut tcpd, from_peer(TCP_ACK, seq, ack, &[1]));
+        let answer = sent(&mut tcpd);
+        assert_eq!(answer.len(), 1);
+        assert_eq!(answer[0].header.flags.get() & TCP_ACK, TCP_ACK);
+        assert_eq!(answer[0].header.ack_num.get(), seq);
+        assert_eq!(answer[0].header.window_size.get(), 0);
+
+        let handle = tcp_handle(&mut tcpd, id);
+        assert_eq!(handle.rcv_nxt, seq);
+        assert_eq!(handle.data.len(), segments);
+    }
+
+    #[test]
+    fn nagle_coalesces() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        // Only the first small write goes out while it is unacknowledged
+        for _ in 0 .. 3 {
+            assert_eq!(write(&mut tcpd, id, b"a").ok(), Some(1));
+        }
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].data, b"a");
+
+        // Its ACK releases the rest as one segment
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, start.wrapping_add(1), &[]));
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].data, b"aa");
+        assert_eq!(segments[0].header.sequence.get(), start.wrapping_add(1));
+    }
+
+    #[test]
+    fn nodelay() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+
+        let setting = tcpd.dup(id, b"nodelay").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &[1]).ok(), Some(1));
+        tcpd.close(setting).unwrap();
+
+        // Every small write goes out right away
+        for _ in 0 .. 3 {
+            assert_eq!(write(&mut tcpd, id, b"a").ok(), Some(1));
+        }
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments.len(), 3);
+        assert!(segments.iter().all(|segment| segment.data == b"a"));
+    }
+
+    #[test]
+    fn nonblocking_connect_in_progress() {
+        let mut tcpd = tcpd();
+        let id = tcpd.open(b"10.0.0.2:80/10.0.0.1:5000", O_RDWR | O_NONBLOCK, 0, 0).ok().unwrap();
+        let seq = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        let mut buf = [0; 16];
+        assert_eq!(tcpd.read(id, &mut buf).err().map(|err| err.errno), Some(EINPROGRESS));
+        assert_eq!(write(&mut tcpd, id, b"a").err().map(|err| err.errno), Some(EINPROGRESS));
+
+        // Once the handshake finishes there is simply nothing to read yet
+        deliver(&mut tcpd, from_peer(TCP_SYN | TCP_ACK, PEER_ISN, seq, &[]));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Established);
+        assert_eq!(tcpd.read(id, &mut buf).ok(), Some(0));
+    }
+
+    #[test]
+    fn receiver_sws() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        fill_window(&mut tcpd, id);
+        let threshold = cmp::min(local_mss() as u32, RECEIVE_BUFFER as u32 / 2);
+
+        // Reading a byte at a time only reopens the window in steps of the threshold
+        let mut buf = [0; 1];
+        let mut window = 0;
+        for i in 1 ..= 3 * threshold {
+            assert_eq!(tcpd.read(id, &mut buf).ok(), Some(1));
+            let updates = sent(&mut tcpd);
+            if i % threshold == 0 {
+                assert_eq!(updates.len(), 1);
+                assert_eq!(updates[0].header.window_size.get() as u32, window + threshold);
+                window += threshold;
+            } else {
+                assert!(updates.is_empty());
+            }
+        }
+        assert_eq!(tcp_handle(&mut tcpd, id).window(), window);
+    }
+
+    #[test]
+    fn large_write() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+        let data: Vec<u8> = (0 .. 1 << 20).map(|i| (i % 251) as u8).collect();
+
+        // Keep writing what the send buffer takes and acknowledging what goes out
+        let mut written = 0;
+        let mut received = Vec::new();
+        while received.len() < data.len() {
+            if written < data.len() {
+                if let Ok(count) = write(&mut tcpd, id, &data[written ..]) {
+                    written += count;
+                }
+            }
+
+            let segments = sent(&mut tcpd);
+            assert!(! segments.is_empty());
+            for segment in segments {
+                assert!(segment.data.len() <= DEFAULT_MSS as usize);
+                assert_eq!(segment.header.sequence.get(), start.wrapping_add(received.len() as u32));
+                received.extend_from_slice(&segment.data);
+            }
+            deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, start.wrapping_add(received.len() as u32), &[]));
+        }
+
+        assert!(received == data);
+        let handle = tcp_handle(&mut tcpd, id);
+        assert_eq!(handle.snd_una, start.wrapping_add(data.len() as u32));
+        assert!(handle.unacked.is_empty());
+        assert!(handle.send_buf.is_empty());
+    }
+
+    #[test]
+    fn sack_blocks() {
+        let mut tcpd = tcpd();
+        let id = established_with(&mut tcpd, &sack_permitted_option());
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        assert!(tcp_handle(&mut tcpd, id).sack);
+
+        // Segments 1, 3 and 4 of 100 bytes each arrive, 2 is missing
+        for &i in &[0, 2, 3] {
+            deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1 + i * 100, ack, &[i as u8; 100]));
+        }
+
+        let answer = sent(&mut tcpd).pop().unwrap();
+        let left = PEER_ISN + 201;
+        let right = PEER_ISN + 401;
+        assert_eq!(answer.header.ack_num.get(), PEER_ISN + 101);
+        assert_eq!(answer.options, vec![
+            1, 1, 5, 10,
+            (left >> 24) as u8, (left >> 16) as u8, (left >> 8) as u8, left as u8,
+            (right >> 24) as u8, (right >> 16) as u8, (right >> 8) as u8, right as u8
+        ]);
+    }
+
+    /// A duplicate ACK from the peer of `established`, SACKing `blocks`
+    fn sack_from_peer(ack: u32, blocks: &[(u32, u32)]) -> (Ipv4, Tcp) {
+        let mut options = vec![1, 1, 5, 2 + 8 * blocks.len() as u8];
+        for edge in blocks.iter().flat_map(|&(left, right)| vec![left, right]) {
+            options.extend_from_slice(&[(edge >> 24) as u8, (edge >> 16) as u8, (edge >> 8) as u8, edge as u8]);
+        }
+        with_options(from_peer(TCP_ACK, PEER_ISN + 1, ack, &[]), &options)
+    }
+
+    #[test]
+    fn sack_one_lost() {
+        let mut tcpd = tcpd();
+        let id = established_with(&mut tcpd, &sack_permitted_option());
+
+        tcp_handle(&mut tcpd, id).cwnd = 10 * DEFAULT_MSS as u32;
+        let data = vec![0x5A; 10 * DEFAULT_MSS as usize];
+        assert_eq!(write(&mut tcpd, id, &data).ok(), Some(data.len()));
+
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments.len(), 10);
+        let lost = segments[0].header.sequence.get();
+        let end = |tcp: &Tcp| tcp.header.sequence.get().wrapping_add(tcp.data.len() as u32);
+
+        // The first segment never arrives, the peer SACKs each of the other nine as they do
+        let mut retransmitted = Vec::new();
+        for received in segments[1 ..].iter() {
+            deliver(&mut tcpd, sack_from_peer(lost, &[(segments[1].header.sequence.get(), end(received))]));
+            retransmitted.extend(sent(&mut tcpd));
+        }
+
+        assert_eq!(retransmitted.len(), 1);
+        assert_eq!(retransmitted[0].header.sequence.get(), lost);
+        assert_eq!(retransmitted[0].data, segments[0].data);
+
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, end(&segments[9]), &[]));
+        assert!(sent(&mut tcpd).iter().all(|tcp| tcp.data.is_empty()));
+        assert!(tcp_handle(&mut tcpd, id).unacked.is_empty());
+    }
+
+    #[test]
+    fn timestamps_negotiated() {
+        let mut tcpd = tcpd();
+        let id = tcpd.open(b"10.0.0.2:80/10.0.0.1:5000", O_RDWR, 0, 0).ok().unwrap();
+        let syn = sent(&mut tcpd).pop().unwrap();
+        let (tsval, _) = parse_timestamps(&syn.options).unwrap();
+
+        let seq = tcp_handle(&mut tcpd, id).snd_nxt;
+        deliver(&mut tcpd, with_options(from_peer(TCP_SYN | TCP_ACK, PEER_ISN, seq, &[]), &timestamps_option(7777, tsval)));
+
+        let handle = tcp_handle(&mut tcpd, id);
+        assert_eq!(handle.state, State::Established);
+        assert!(handle.timestamps);
+        assert_eq!(handle.ts_recent, 7777);
+
+        let ack = sent(&mut tcpd).pop().unwrap();
+        assert_eq!(parse_timestamps(&ack.options).map(|(_, tsecr)| tsecr), Some(7777));
+    }
+
+    #[test]
+    fn timestamps_echoed() {
+        let mut tcpd = tcpd();
+        let id = established_with(&mut tcpd, &timestamps_option(7777, 0));
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        // Each segment echoes the latest timestamp from the peer
+        deliver(&mut tcpd, with_options(from_peer(TCP_ACK, PEER_ISN + 1, ack, b"a"), &timestamps_option(8888, 0)));
+        assert_eq!(write(&mut tcpd, id, b"b").ok(), Some(1));
+        let segment = sent(&mut tcpd).pop().unwrap();
+        assert_eq!(segment.data, b"b");
+        assert_eq!(parse_timestamps(&segment.options).map(|(_, tsecr)| tsecr), Some(8888));
+
+        // The peer's ACK echoes ours back along with its next timestamp
+        let (tsval, _) = parse_timestamps(&segment.options).unwrap();
+        deliver(&mut tcpd, with_options(from_peer(TCP_ACK, PEER_ISN + 2, ack.wrapping_add(1), &[]), &timestamps_option(9999, tsval)));
+        let handle = tcp_handle(&mut tcpd, id);
+        assert!(handle.unacked.is_empty());
+        assert_eq!(handle.ts_recent, 9999);
+    }
+
+    #[test]
+    fn timestamps_fallback() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        assert!(! tcp_handle(&mut tcpd, id).timestamps);
+
+        // A peer that did not answer with timestamps never gets them
+        assert_eq!(write(&mut tcpd, id, b"a").ok(), Some(1));
+        let segment = sent(&mut tcpd).pop().unwrap();
+        assert_eq!(parse_timestamps(&segment.options), None);
+    }
+
+    /// The value our own timestamps option carries, which depends on the clock
+    fn tsval(tcp: &Tcp) -> [u8; 4] {
+        let (tsval, _) = parse_timestamps(&tcp.options).unwrap();
+        [(tsval >> 24) as u8, (tsval >> 16) as u8, (tsval >> 8) as u8, tsval as u8]
+    }
+
+    #[test]
+    fn golden_syn() {
+        let mut tcpd = tcpd();
+        let id = connect(&mut tcpd);
+        let handle = tcp_handle(&mut tcpd, id);
+        handle.snd_nxt = 0x01020304;
+
+        let tcp = handle.create_tcp(TCP_SYN, Vec::new());
+        let ts = tsval(&tcp);
+        assert_eq!(tcp.to_bytes(), vec![
+            0x13, 0x88, 0x00, 0x50,
+            0x01, 0x02, 0x03, 0x04,
+            0x00, 0x00, 0x00, 0x00,
+            0xB0, 0x02, 0xFF, 0xFF,
+            0x00, 0x00, 0x00, 0x00,
+            // MSS 1460
+            0x02, 0x04, 0x05, 0xB4,
+            // Window scale 3
+            0x01, 0x03, 0x03, 0x03,
+            // SACK permitted
+            0x01, 0x01, 0x04, 0x02,
+            // Timestamps, echoing nothing yet
+            0x01, 0x01, 0x08, 0x0A,
+            ts[0], ts[1], ts[2], ts[3],
+            0x00, 0x00, 0x00, 0x00
+        ]);
+    }
+
+    #[test]
+    fn golden_syn_ack() {
+        let mut tcpd = tcpd();
+        let id = connect(&mut tcpd);
+        let handle = tcp_handle(&mut tcpd, id);
+        handle.state = State::SynReceived;
+        handle.snd_nxt = 0x01020304;
+        handle.rcv_nxt = 0x0A0B0C0D;
+        handle.sack = true;
+        handle.timestamps = true;
+        handle.ts_recent = 0x11223344;
+
+        let tcp = handle.create_tcp(TCP_SYN | TCP_ACK, Vec::new());
+        let ts = tsval(&tcp);
+        assert_eq!(tcp.to_bytes(), vec![
+            0x13, 0x88, 0x00, 0x50,
+            0x01, 0x02, 0x03, 0x04,
+            0x0A, 0x0B, 0x0C, 0x0D,
+            0xB0, 0x12, 0xFF, 0xFF,
+            0x00, 0x00, 0x00, 0x00,
+            0x02, 0x04, 0x05, 0xB4,
+            0x01, 0x03, 0x03, 0x03,
+            0x01, 0x01, 0x04, 0x02,
+            0x01, 0x01, 0x08, 0x0A,
+            ts[0], ts[1], ts[2], ts[3],
+            0x11, 0x22, 0x33, 0x44
+        ]);
+    }
+
+    #[test]
+    fn golden_ack() {
+        let mut tcpd = tcpd();
+        let id = connect(&mut tcpd);
+        let handle = tcp_handle(&mut tcpd, id);
+        handle.state = State::Established;
+        handle.snd_nxt = 0x01020304;
+        handle.rcv_nxt = 0x0A0B0C0D;
+
+        // The window is scaled by 3, so 262144 bytes are advertised as 32768
+        assert_eq!(handle.create_tcp(TCP_ACK, Vec::new()).to_bytes(), vec![
+            0x13, 0x88, 0x00, 0x50,
+            0x01, 0x02, 0x03, 0x04,
+            0x0A, 0x0B, 0x0C, 0x0D,
+            0x50, 0x10, 0x80, 0x00,
+            0x00, 0x00, 0x00, 0x00
+        ]);
+
+        handle.timestamps = true;
+        handle.ts_recent = 0x11223344;
+        let tcp = handle.create_tcp(TCP_ACK, Vec::new());
+        let ts = tsval(&tcp);
+        assert_eq!(tcp.to_bytes(), vec![
+            0x13, 0x88, 0x00, 0x50,
+            0x01, 0x02, 0x03, 0x04,
+            0x0A, 0x0B, 0x0C, 0x0D,
+            0x80, 0x10, 0x80, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0x01, 0x01, 0x08, 0x0A,
+            ts[0], ts[1], ts[2], ts[3],
+            0x11, 0x22, 0x33, 0x44
+        ]);
+    }
+
+    /// An option list with the kind and value of each option that should be found in it
+    type OptionList = (&'static [u8], &'static [(u8, &'static [u8])]);
+
+    const OPTIONS: &[OptionList] = &[
+        (&[], &[]),
+        (&[2, 4, 0x05, 0xB4], &[(2, &[0x05, 0xB4])]),
+        (&[1, 1, 3, 3, 7], &[(3, &[7])]),
+        // A zero length byte would never advance
+        (&[2, 0, 0x05, 0xB4], &[]),
+        // A length of one is shorter than the kind and length themselves
+        (&[3, 1, 7], &[]),
+        // A length running past the end
+        (&[2, 4, 0x05, 0xB4, 8, 10, 1, 2], &[(2, &[0x05, 0xB4])]),
+        (&[2, 255], &[]),
+        // A lone kind byte
+        (&[2], &[]),
+        (&[1, 1, 1, 8], &[]),
+        // Nothing after an end of option list counts, even if it looks like an option
+        (&[0, 2, 4, 0x05, 0xB4], &[]),
+        (&[3, 3, 7, 0, 0xFF, 0xFF, 0xFF], &[(3, &[7])]),
+    ];
+
+    #[test]
+    fn options_malformed() {
+        for &(options, expected) in OPTIONS.iter() {
+            let found: Vec<(u8, &[u8])> = Options::new(options).collect();
+            assert_eq!(found, expected.to_vec(), "options {:?}", options);
+        }
+    }
+
+    /// Check that an option list of any bytes parses without panicking
+    fn parse_any(options: &[u8]) {
+        for option in Options::new(options) {
+            assert!(option.1.len() + 2 <= options.len(), "options {:?}", options);
+        }
+        let _ = parse_mss(options);
+        let _ = parse_wscale(options);
+        let _ = parse_sack_permitted(options);
+        let _ = parse_timestamps(options);
+        let _ = parse_sack(options);
+    }
+
+    #[test]
+    fn options_truncated() {
+        // Every prefix of a full option list parses without panicking, to what fits in it
+        let options = [2, 4, 0x05, 0xB4, 1, 3, 3, 7, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 2, 5, 10, 0, 0, 0, 1, 0, 0, 0, 2];
+        for len in 0 .. options.len() + 1 {
+            parse_any(&options[.. len]);
+        }
+    }
+
+    #[test]
+    fn options_random() {
+        // Every list of up to two bytes
+        parse_any(&[]);
+        for a in 0 ..= 255 {
+            parse_any(&[a]);
+            for b in 0 ..= 255 {
+                parse_any(&[a, b]);
+            }
+        }
+
+        // And longer ones from a fixed xorshift sequence, with small kinds and lengths made
+        // likely so that real options turn up among the noise
+        let mut state: u32 = 0x2545_F491;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        for _ in 0 .. 100000 {
+            let len = next() as usize % 41;
+            let options: Vec<u8> = (0 .. len).map(|_| {
+                let value = next();
+                if value & 1 == 0 { (value >> 8) as u8 % 12 } else { (value >> 8) as u8 }
+            }).collect();
+            parse_any(&options);
+        }
+    }
+
+    #[test]
+    fn options_bad_values() {
+        // Options of the right kind but the wrong length are ignored
+        assert_eq!(parse_mss(&[2, 3, 5]), None);
+        assert_eq!(parse_mss(&[2, 4, 0, 0]), None);
+        assert_eq!(parse_wscale(&[3, 2]), None);
+        assert_eq!(parse_timestamps(&[8, 6, 0, 0, 0, 1]), None);
+        assert!(! parse_sack_permitted(&[4, 3, 0]));
+        assert_eq!(parse_sack(&[5, 6, 0, 0, 0, 1]), vec![]);
+    }
+
+    fn header(offset: u8, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        if len > 12 {
+            bytes[12] = offset << 4;
+        }
+        bytes
+    }
+
+    #[test]
+    fn data_offset() {
+        assert!(data_offset_ok(&header(5, 20)));
+        assert!(data_offset_ok(&header(6, 24)));
+        assert!(data_offset_ok(&header(15, 60)));
+        assert!(data_offset_ok(&header(5, 100)));
+
+        // Below the five words of the header itself
+        for offset in 0 .. 5 {
+            assert!(! data_offset_ok(&header(offset, 60)), "offset {}", offset);
+        }
+
+        // Past the end of the segment
+        assert!(! data_offset_ok(&header(6, 20)));
+        assert!(! data_offset_ok(&header(15, 59)));
+
+        // Too short for a header at all
+        assert!(! data_offset_ok(&header(5, 19)));
+        assert!(! data_offset_ok(&[]));
+    }
+
+    #[test]
+    fn ecn_congestion() {
+        let mut tcpd = tcpd();
+        let id = connect(&mut tcpd);
+        tcp_handle(&mut tcpd, id).ecn = true;
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+        deliver(&mut tcpd, from_peer(TCP_SYN | TCP_ACK | TCP_ECE, PEER_ISN, start, &[]));
+        assert!(tcp_handle(&mut tcpd, id).ecn_ok);
+        sent(&mut tcpd);
+
+        // Congestion marked on data from the peer is echoed until the peer says it reacted
+        let mut marked = from_peer(TCP_ACK, PEER_ISN + 1, start, b"a");
+        marked.0.header.services = IP_CE;
+        deliver(&mut tcpd, marked);
+        assert_eq!(write(&mut tcpd, id, b"b").ok(), Some(1));
+        let segment = sent(&mut tcpd).pop().unwrap();
+        assert_eq!(segment.header.flags.get() & TCP_ECE, TCP_ECE);
+
+        deliver(&mut tcpd, from_peer(TCP_ACK | TCP_CWR, PEER_ISN + 2, start, &[]));
+        assert!(! tcp_handle(&mut tcpd, id).ece);
+
+        // The peer echoes congestion marked on our data, which halves the congestion window
+        // without retransmitting anything, and our next data says so
+        tcp_handle(&mut tcpd, id).cwnd = 10 * DEFAULT_MSS as u32;
+        deliver(&mut tcpd, from_peer(TCP_ACK | TCP_ECE, PEER_ISN + 2, start.wrapping_add(1), &[]));
+        assert!(sent(&mut tcpd).is_empty());
+        assert_eq!(tcp_handle(&mut tcpd, id).cwnd, 5 * DEFAULT_MSS as u32);
+
+        assert_eq!(write(&mut tcpd, id, b"c").ok(), Some(1));
+        let segment = sent(&mut tcpd).pop().unwrap();
+        assert_eq!(segment.data, b"c");
+        assert_eq!(segment.header.flags.get() & (TCP_CWR | TCP_ECE), TCP_CWR);
+    }
+
+    #[test]
+    fn stats() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let stats = tcpd.dup(id, b"stats").ok().unwrap();
+
+        let mut buf = [0; 512];
+        let len = tcpd.read(stats, &mut buf).ok().unwrap();
+        let text = str::from_utf8(&buf[.. len]).unwrap();
+        assert!(text.starts_with("state: established\nbytes_sent: 0\n"), "{}", text);
+
+        // Each read is a fresh snapshot
+        assert_eq!(write(&mut tcpd, id, b"abc").ok(), Some(3));
+        let len = tcpd.read(stats, &mut buf).ok().unwrap();
+        let text = str::from_utf8(&buf[.. len]).unwrap();
+        assert!(text.contains("\nbytes_sent: 3\n"), "{}", text);
+    }
+
+    #[test]
+    fn connections_list() {
+        let mut tcpd = tcpd();
+        let id = connect(&mut tcpd);
+        assert_eq!(tcpd.list_connections(true, 0), b"10.0.0.1:5000 10.0.0.2:80 syn_sent\n".to_vec());
+
+        tcp_handle(&mut tcpd, id).uid = 1000;
+        assert_eq!(tcpd.list_connections(false, 1000), tcpd.list_connections(true, 0));
+        assert!(tcpd.list_connections(false, 0).is_empty());
+    }
+
+    /// Bytes `start .. end` of a stream from the peer of `established`, acknowledging `ack`
+    fn stream_from_peer(ack: u32, start: usize, end: usize) -> (Ipv4, Tcp) {
+        let data: Vec<u8> = (start .. end).map(|i| i as u8).collect();
+        from_peer(TCP_ACK, PEER_ISN + 1 + start as u32, ack, &data)
+    }
+
+    /// Read everything a connection has received so far
+    fn read_all(tcpd: &mut Tcpd, id: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+        while let Ok(count) = tcpd.read(id, &mut buf) {
+            if count == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[.. count]);
+        }
+        data
+    }
+
+    /// Deliver byte ranges of the peer's stream in order, then check the connection received
+    /// bytes `0 .. len` exactly once
+    fn reassemble(ranges: &[(usize, usize)], len: usize) {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        for &(start, end) in ranges {
+            deliver(&mut tcpd, stream_from_peer(ack, start, end));
+        }
+
+        assert_eq!(tcp_handle(&mut tcpd, id).rcv_nxt, PEER_ISN + 1 + len as u32);
+        assert!(tcp_handle(&mut tcpd, id).reassembly.is_empty());
+        assert_eq!(read_all(&mut tcpd, id), (0 .. len).map(|i| i as u8).collect::<Vec<u8>>());
+
+        // Whatever overlapped was answered with our current edge
+        let last = sent(&mut tcpd).pop().unwrap();
+        assert_eq!(last.header.ack_num.get(), PEER_ISN + 1 + len as u32);
+    }
+
+    #[test]
+    fn reassemble_duplicate() {
+        reassemble(&[(0, 100), (0, 100)], 100);
+        reassemble(&[(0, 100), (200, 300), (200, 300), (100, 200)], 300);
+    }
+
+    #[test]
+    fn reassemble_left_overlap() {
+        // Starts before what was already received
+        reassemble(&[(0, 100), (50, 150)], 150);
+        reassemble(&[(0, 100), (200, 300), (150, 250), (100, 150)], 300);
+    }
+
+    #[test]
+    fn reassemble_right_overlap() {
+        // Runs into data queued past a gap
+        reassemble(&[(100, 200), (0, 150)], 200);
+        reassemble(&[(0, 50), (150, 300), (100, 200), (50, 100)], 300);
+    }
+
+    #[test]
+    fn reassemble_contained() {
+        reassemble(&[(0, 200), (50, 100)], 200);
+        reassemble(&[(100, 300), (150, 200), (0, 100)], 300);
+    }
+
+    #[test]
+    fn bare_acks_absorbed() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+        assert_eq!(write(&mut tcpd, id, b"abc").ok(), Some(3));
+        sent(&mut tcpd);
+
+        // An ACK for new data, a duplicate of it and a window update all go unanswered
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, start.wrapping_add(3), &[]));
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, start.wrapping_add(3), &[]));
+        let mut update = from_peer(TCP_ACK, PEER_ISN + 1, start.wrapping_add(3), &[]);
+        update.1.header.window_size = n16::new(1000);
+        deliver(&mut tcpd, update);
+
+        assert!(sent(&mut tcpd).is_empty());
+        let handle = tcp_handle(&mut tcpd, id);
+        assert_eq!(handle.snd_una, start.wrapping_add(3));
+        assert_eq!(handle.snd_wnd, 1000);
+    }
+
+    #[test]
+    fn fin_with_data() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        deliver(&mut tcpd, from_peer(TCP_PSH | TCP_ACK | TCP_FIN, PEER_ISN + 1, ack, b"last"));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::CloseWait);
+        assert_eq!(tcp_handle(&mut tcpd, id).rcv_nxt, PEER_ISN + 1 + 4 + 1);
+
+        // One ACK covers both the data and the FIN
+        let answer = sent(&mut tcpd);
+        assert_eq!(answer.len(), 1);
+        assert_eq!(answer[0].header.ack_num.get(), PEER_ISN + 1 + 4 + 1);
+
+        // The data is still delivered, followed by end of file
+        assert_eq!(read_all(&mut tcpd, id), b"last");
+        let mut buf = [0; 16];
+        assert_eq!(tcpd.read(id, &mut buf).ok(), Some(0));
+    }
+
+    #[test]
+    fn fin_with_data_half_closed() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+
+        // Shut down writing only, and let the peer acknowledge our FIN
+        let setting = tcpd.dup(id, b"shutdown").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &[SHUT_WR]).ok(), Some(1));
+        tcpd.close(setting).unwrap();
+        assert_eq!(sent(&mut tcpd).len(), 1);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, ack, &[]));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::FinWait2);
+
+        // The response and the peer's FIN come in one segment
+        deliver(&mut tcpd, from_peer(TCP_PSH | TCP_ACK | TCP_FIN, PEER_ISN + 1, ack, b"response"));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::TimeWait);
+        assert_eq!(tcp_handle(&mut tcpd, id).rcv_nxt, PEER_ISN + 1 + 8 + 1);
+
+        let answer = sent(&mut tcpd);
+        assert_eq!(answer.len(), 1);
+        assert_eq!(answer[0].header.flags.get() & 0xFFF, TCP_ACK);
+        assert_eq!(answer[0].header.ack_num.get(), PEER_ISN + 1 + 8 + 1);
+
+        assert_eq!(read_all(&mut tcpd, id), b"response");
+        let mut buf = [0; 16];
+        assert_eq!(tcpd.read(id, &mut buf).ok(), Some(0));
+    }
+
+    #[test]
+    fn seq_properties() {
+        // Pseudo-random points, with the space around the wrap point made likely
+        let mut state: u32 = 0x9E37_79B9;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        for _ in 0 .. 100000 {
+            let a = if next() & 1 == 0 { next() } else { 0u32.wrapping_sub(next() % 0x1000) };
+            let d = next() % 0x8000_0000;
+
+            let b = a.wrapping_add(d);
+            assert_eq!(seq_lt(a, b), d != 0, "{:x} {:x}", a, b);
+            assert!(seq_leq(a, b), "{:x} {:x}", a, b);
+            assert!(! seq_gt(a, b), "{:x} {:x}", a, b);
+            assert_eq!(seq_gt(b, a), d != 0, "{:x} {:x}", a, b);
+
+            // Anything within d of a is in a window of d + 1 starting there, and the next
+            // number is not
+            let inside = a.wrapping_add(next() % (d + 1));
+            assert!(seq_in_window(inside, a, d + 1), "{:x} {:x} {:x}", inside, a, d + 1);
+            assert!(! seq_in_window(b.wrapping_add(1), a, d + 1), "{:x} {:x} {:x}", b, a, d + 1);
+        }
+    }
+
+    /// An `established` connection with two full segments in flight, and where they start
+    fn two_segments(tcpd: &mut Tcpd) -> (usize, u32) {
+        let id = established(tcpd);
+        let start = tcp_handle(tcpd, id).snd_nxt;
+        assert_eq!(write(tcpd, id, &[0; 2 * DEFAULT_MSS as usize]).ok(), Some(2 * DEFAULT_MSS as usize));
+        assert_eq!(sent(tcpd).len(), 2);
+        assert_eq!(tcp_handle(tcpd, id).unacked.len(), 2);
+        (id, start)
+    }
+
+    #[test]
+    fn acknowledge_cumulative() {
+        let mut tcpd = tcpd();
+        let (id, start) = two_segments(&mut tcpd);
+        let end = start.wrapping_add(2 * DEFAULT_MSS as u32);
+        let time = monotonic().ok().unwrap();
+
+        let handle = tcp_handle(&mut tcpd, id);
+        assert!(handle.acknowledge(end, None, &time));
+        assert!(handle.unacked.is_empty());
+        assert_eq!(handle.snd_una, end);
+
+        // The same ACK again is a duplicate and changes nothing
+        assert!(! handle.acknowledge(end, None, &time));
+        assert_eq!(handle.snd_una, end);
+    }
+
+    #[test]
+    fn acknowledge_partial() {
+        let mut tcpd = tcpd();
+        let (id, start) = two_segments(&mut tcpd);
+        let middle = start.wrapping_add(DEFAULT_MSS as u32);
+        let end = start.wrapping_add(2 * DEFAULT_MSS as u32);
+        let time = monotonic().ok().unwrap();
+
+        let handle = tcp_handle(&mut tcpd, id);
+        assert!(handle.acknowledge(middle, None, &time));
+        assert_eq!(handle.unacked.len(), 1);
+        assert_eq!(handle.unacked[0].seq, middle);
+        assert_eq!(handle.snd_una, middle);
+
+        // Part of a segment moves snd_una, but the segment stays queued until all of it is
+        let inside = middle.wrapping_add(100);
+        assert!(! handle.acknowledge(inside, None, &time));
+        assert_eq!(handle.unacked.len(), 1);
+        assert_eq!(handle.snd_una, inside);
+
+        assert!(handle.acknowledge(end, None, &time));
+        assert!(handle.unacked.is_empty());
+        assert_eq!(handle.snd_una, end);
+
+        // Nothing past what was sent is accepted
+        assert!(! handle.acknowledge(end.wrapping_add(1), None, &time));
+        assert_eq!(handle.snd_una, end);
+    }
+
+    #[test]
+    fn keepalive_give_up() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let snd_nxt = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        let setting = tcpd.dup(id, b"keepalive").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &[1]).ok(), Some(1));
+        tcpd.close(setting).unwrap();
+        let start = monotonic().ok().unwrap();
+
+        // Idle for two hours, then probed every 75 seconds with a byte the peer already has
+        let mut elapsed = KEEPALIVE_IDLE;
+        for _ in 0 .. KEEPALIVE_PROBES {
+            at(&mut tcpd, add_time(&start, &millis(elapsed)));
+            let probe = sent(&mut tcpd);
+            assert_eq!(probe.len(), 1);
+            assert_eq!(probe[0].header.flags.get() & 0xFFF, TCP_ACK);
+            assert_eq!(probe[0].header.sequence.get(), snd_nxt.wrapping_sub(1));
+            elapsed += KEEPALIVE_INTERVAL;
+        }
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Established);
+
+        // None of them was answered, so the connection is reset and fails
+        at(&mut tcpd, add_time(&start, &millis(elapsed)));
+        let reset = sent(&mut tcpd);
+        assert_eq!(reset.len(), 1);
+        assert_eq!(reset[0].header.flags.get() & 0xFFF, TCP_RST | TCP_ACK);
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Closed);
+
+        let mut buf = [0; 16];
+        assert_eq!(tcpd.read(id, &mut buf).err().map(|err| err.errno), Some(ETIMEDOUT));
+    }
+
+    #[test]
+    fn pure_acks_between_data() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        deliver(&mut tcpd, stream_from_peer(ack, 0, 100));
+
+        // A window update, and keepalive style ACKs from one byte back, carry nothing
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 101, ack, &[]));
+        for _ in 0 .. 3 {
+            deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 100, ack, &[]));
+        }
+        assert_eq!(tcp_handle(&mut tcpd, id).rcv_nxt, PEER_ISN + 101);
+
+        deliver(&mut tcpd, stream_from_peer(ack, 100, 200));
+        assert_eq!(tcp_handle(&mut tcpd, id).rcv_nxt, PEER_ISN + 201);
+        assert_eq!(read_all(&mut tcpd, id), (0 .. 200).map(|i| i as u8).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn fpath_before_connect() {
+        let mut tcpd = tcpd();
+        let listener = tcpd.open(b"/10.0.0.1:8080", O_RDWR, 0, 0).ok().unwrap();
+        let id = tcpd.open(b"10.0.0.2:80/10.0.0.1:0", O_RDWR, 0, 0).ok().unwrap();
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::SynSent);
+
+        // The connecting handle already reports the ephemeral port it was given
+        let port = tcp_handle(&mut tcpd, id).local.1;
+        assert!(port >= 32768);
+
+        for &(file, remote, local) in [(listener, (Ipv4Addr::NULL, 0), 8080), (id, (Ipv4Addr::from_str("10.0.0.2"), 80), port)].iter() {
+            let mut buf = [0; 64];
+            let count = tcpd.fpath(file, &mut buf).ok().unwrap();
+            let path = str::from_utf8(&buf[.. count]).unwrap();
+            assert_eq!(path, format!("tcp:{}:{}/10.0.0.1:{}", remote.0.to_string(), remote.1, local));
+
+            let mut parts = path["tcp:".len() ..].split("/");
+            assert_eq!(parse_socket(parts.next().unwrap()), remote);
+            assert_eq!(parse_socket(parts.next().unwrap()), (Ipv4Addr::from_str("10.0.0.1"), local));
+        }
+    }
+
+    /// A connection accepted from a listener on the local end of `connect`, whose peer has sent
+    /// its SYN. Returns the listener and the connection, which has answered with a SYN-ACK
+    fn accepted(tcpd: &mut Tcpd) -> (usize, usize) {
+        let listener = tcpd.open(b"/10.0.0.1:5000", O_RDWR, 0, 0).ok().unwrap();
+        deliver(tcpd, from_peer(TCP_SYN, PEER_ISN, 0, &[]));
+        let id = tcpd.dup(listener, b"listen").ok().unwrap();
+        assert_eq!(tcp_handle(tcpd, id).state, State::SynReceived);
+        sent(tcpd);
+        (listener, id)
+    }
+
+    #[test]
+    fn handshake_ack_with_data() {
+        let mut tcpd = tcpd();
+        let (_listener, id) = accepted(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        // The client sends its request with the ACK of our SYN-ACK
+        deliver(&mut tcpd, from_peer(TCP_PSH | TCP_ACK, PEER_ISN + 1, ack, b"request"));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Established);
+        assert_eq!(tcp_handle(&mut tcpd, id).rcv_nxt, PEER_ISN + 1 + 7);
+        assert_eq!(read_all(&mut tcpd, id), b"request");
+    }
+
+    #[test]
+    fn handshake_ack_sequence() {
+        let mut tcpd = tcpd();
+        let (_listener, id) = accepted(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        // The right acknowledgement from the wrong place in the peer's stream
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 100, ack, &[]));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::SynReceived);
+
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, ack, &[]));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Established);
+    }
+
+    #[test]
+    fn syn_challenge_ack() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let snd_nxt = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        // Even inside the window a SYN only gets an ACK restating where we are
+        deliver(&mut tcpd, from_peer(TCP_SYN, PEER_ISN + 10, 0, &[]));
+        let answer = sent(&mut tcpd);
+        assert_eq!(answer.len(), 1);
+        assert_eq!(answer[0].header.flags.get() & 0xFFF, TCP_ACK);
+        assert_eq!(answer[0].header.sequence.get(), snd_nxt);
+        assert_eq!(answer[0].header.ack_num.get(), PEER_ISN + 1);
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Established);
+        assert_eq!(tcp_handle(&mut tcpd, id).rcv_nxt, PEER_ISN + 1);
+    }
+
+    #[test]
+    fn retransmitted_data_reacked() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        // Our ACK of the first copy is lost
+        deliver(&mut tcpd, stream_from_peer(ack, 0, 100));
+        sent(&mut tcpd);
+
+        deliver(&mut tcpd, stream_from_peer(ack, 0, 100));
+        let answer = sent(&mut tcpd);
+        assert_eq!(answer.len(), 1);
+        assert_eq!(answer[0].header.ack_num.get(), PEER_ISN + 101);
+        assert_eq!(read_all(&mut tcpd, id), (0 .. 100).map(|i| i as u8).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn handshake_ack_lost() {
+        let mut tcpd = tcpd();
+        let (_listener, id) = accepted(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        let start = monotonic().ok().unwrap();
+
+        // The ACK of our SYN-ACK never arrives, so the SYN-ACK is sent again
+        at(&mut tcpd, add_time(&start, &millis(SYN_RTO_INITIAL)));
+        let retransmit = sent(&mut tcpd);
+        assert_eq!(retransmit.len(), 1);
+        assert_eq!(retransmit[0].header.flags.get() & 0xFFF, TCP_SYN | TCP_ACK);
+        assert_eq!(retransmit[0].header.sequence.get(), ack.wrapping_sub(1));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::SynReceived);
+
+        // The client's first data acknowledges it and completes the handshake
+        deliver(&mut tcpd, from_peer(TCP_PSH | TCP_ACK, PEER_ISN + 1, ack, b"request"));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Established);
+        assert_eq!(read_all(&mut tcpd, id), b"request");
+    }
+
+    #[test]
+    fn ephemeral_ports() {
+        let mut tcpd = tcpd();
+        let first = tcpd.open(b"10.0.0.2:80", O_RDWR, 0, 0).ok().unwrap();
+        let second = tcpd.open(b"10.0.0.2:80", O_RDWR, 0, 0).ok().unwrap();
+
+        // Both get a port from the ephemeral range, and not the same one
+        let first = tcp_handle(&mut tcpd, first).local.1;
+        let second = tcp_handle(&mut tcpd, second).local.1;
+        assert!(first >= 32768 && second >= 32768);
+        assert!(first != second);
+    }
+
+    /// Everything two daemons sent each other since the last call, delivered at once as if the
+    /// segments crossed on the wire
+    fn exchange(a: &mut Tcpd, b: &mut Tcpd) {
+        let to_b = sent_ip(a);
+        let to_a = sent_ip(b);
+        for segment in to_b {
+            deliver(b, segment);
+        }
+        for segment in to_a {
+            deliver(a, segment);
+        }
+    }
+
+    #[test]
+    fn simultaneous_close() {
+        let mut client = tcpd();
+        let mut server = tcpd();
+        let listener = server.open(b"/10.0.0.2:80", O_RDWR, 0, 0).ok().unwrap();
+        let a = client.open(b"10.0.0.2:80/10.0.0.1:5000", O_RDWR, 0, 0).ok().unwrap();
+        exchange(&mut client, &mut server);
+        let b = server.dup(listener, b"listen").ok().unwrap();
+        exchange(&mut client, &mut server);
+        exchange(&mut client, &mut server);
+        assert_eq!(tcp_handle(&mut client, a).state, State::Established);
+        assert_eq!(tcp_handle(&mut server, b).state, State::Established);
+
+        // Both FINs are on the wire before either side sees the other's
+        client.close(a).unwrap();
+        server.close(b).unwrap();
+        assert_eq!(tcp_handle(&mut client, a).state, State::FinWait1);
+        assert_eq!(tcp_handle(&mut server, b).state, State::FinWait1);
+
+        exchange(&mut client, &mut server);
+        assert_eq!(tcp_handle(&mut client, a).state, State::Closing);
+        assert_eq!(tcp_handle(&mut server, b).state, State::Closing);
+
+        // The ACKs of the FINs cross as well
+        exchange(&mut client, &mut server);
+        assert_eq!(tcp_handle(&mut client, a).state, State::TimeWait);
+        assert_eq!(tcp_handle(&mut server, b).state, State::TimeWait);
+
+        // Nothing is left once TimeWait is over, apart from the listener
+        let time = add_time(&monotonic().ok().unwrap(), &millis(TIME_WAIT));
+        at(&mut client, time);
+        at(&mut server, time);
+        assert!(sent(&mut client).is_empty() && sent(&mut server).is_empty());
+        assert!(client.handles.is_empty() && client.ports.is_empty());
+        assert_eq!(server.handles.len(), 1);
+        assert_eq!(server.ports.get(&80), Some(&1));
+    }
+
+    /// Set the reuseaddr setting of an unconnected handle
+    fn reuse_addr(tcpd: &mut Tcpd, empty: usize) {
+        let setting = tcpd.dup(empty, b"reuseaddr").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &[1]).ok(), Some(1));
+        tcpd.close(setting).unwrap();
+    }
+
+    #[test]
+    fn reuse_time_wait_port() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        tcp_handle(&mut tcpd, id).state = State::TimeWait;
+
+        // A restarted server can only take the port back if it asks to
+        let plain = tcpd.open(b"", O_RDWR, 0, 0).ok().unwrap();
+        assert_eq!(tcpd.dup(plain, b"/10.0.0.1:5000").err().map(|err| err.errno), Some(EADDRINUSE));
+
+        let reusing = tcpd.open(b"", O_RDWR, 0, 0).ok().unwrap();
+        reuse_addr(&mut tcpd, reusing);
+        let listener = tcpd.dup(reusing, b"/10.0.0.1:5000").ok().unwrap();
+        assert_eq!(tcp_handle(&mut tcpd, listener).state, State::Listen);
+
+        // A second listener needs reuse as well
+        assert_eq!(tcpd.dup(plain, b"/10.0.0.1:5000").err().map(|err| err.errno), Some(EADDRINUSE));
+        tcpd.dup(reusing, b"/10.0.0.1:5000").ok().unwrap();
+        assert_eq!(tcpd.ports.get(&5000), Some(&3));
+
+        // Nor can reuse take over the connection itself
+        assert_eq!(tcpd.dup(reusing, b"10.0.0.2:80/10.0.0.1:5000").err().map(|err| err.errno), Some(EADDRINUSE));
+    }
+
+    #[test]
+    fn open_setting() {
+        let mut tcpd = tcpd();
+        assert_eq!(tcpd.open(b"reuseaddr", O_RDWR, 0, 0).err().map(|err| err.errno), Some(EINVAL));
+        assert_eq!(tcpd.open(b"time_wait", O_RDWR, 0, 0).err().map(|err| err.errno), Some(EINVAL));
+        assert_eq!(tcpd.open(b"maxseg", O_RDWR, 0, 0).err().map(|err| err.errno), Some(EINVAL));
+        assert!(tcpd.handles.is_empty());
+
+        let id = tcpd.open(b"", O_RDWR, 0, 0).ok().unwrap();
+        let setting = tcpd.dup(id, b"reuseaddr").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &[1]).ok(), Some(1));
+        let mut buf = [0];
+        assert_eq!(tcpd.read(setting, &mut buf).ok(), Some(1));
+        assert_eq!(buf, [1]);
+
+        let setting = tcpd.dup(id, b"maxseg").ok().unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(tcpd.read(setting, &mut buf).ok(), Some(8));
+        assert_eq!(usize::from_ne_bytes(buf), local_mss() as usize);
+    }
+
+    #[test]
+    fn syn_ack_lost() {
+        let mut tcpd = tcpd();
+        let (listener, id) = accepted(&mut tcpd);
+        let snd_nxt = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        // The client never saw our SYN-ACK and sends its SYN again
+        deliver(&mut tcpd, from_peer(TCP_SYN, PEER_ISN, 0, &[]));
+        let answer = sent(&mut tcpd);
+        assert_eq!(answer.len(), 1);
+        assert_eq!(answer[0].header.flags.get() & 0xFFF, TCP_SYN | TCP_ACK);
+        assert_eq!(answer[0].header.sequence.get(), snd_nxt.wrapping_sub(1));
+        assert_eq!(answer[0].header.ack_num.get(), PEER_ISN + 1);
+
+        // Answered by the connection, not queued for another accept
+        assert_eq!(tcp_handle(&mut tcpd, id).snd_nxt, snd_nxt);
+        assert_eq!(tcp_handle(&mut tcpd, id).rcv_nxt, PEER_ISN + 1);
+        assert!(tcp_handle(&mut tcpd, listener).data.is_empty());
+
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, snd_nxt, &[]));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Established);
+    }
+
+    #[test]
+    fn syn_queued_once() {
+        let mut tcpd = tcpd();
+        let listener = tcpd.open(b"/10.0.0.1:5000", O_RDWR, 0, 0).ok().unwrap();
+        deliver(&mut tcpd, from_peer(TCP_SYN, PEER_ISN, 0, &[]));
+        deliver(&mut tcpd, from_peer(TCP_SYN, PEER_ISN, 0, &[]));
+        assert_eq!(tcp_handle(&mut tcpd, listener).data.len(), 1);
+    }
+
+    /// Change the window a segment advertises
+    fn with_window(segment: (Ipv4, Tcp), window: u16) -> (Ipv4, Tcp) {
+        let (ip, mut tcp) = segment;
+        tcp.header.window_size = n16::new(window);
+        (ip, tcp)
+    }
+
+    #[test]
+    fn peer_window_limits_send() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+        let window = 2 * DEFAULT_MSS as u32;
+        deliver(&mut tcpd, with_window(from_peer(TCP_ACK, PEER_ISN + 1, start, &[]), window as u16));
+
+        // Only what fits in the peer's window goes out, the rest waits in the buffer
+        assert_eq!(write(&mut tcpd, id, &vec![0; 2 * window as usize]).ok(), Some(2 * window as usize));
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments.iter().map(|tcp| tcp.data.len()).sum::<usize>(), window as usize);
+
+        // Acknowledging it opens the window again, which sends the rest
+        deliver(&mut tcpd, with_window(from_peer(TCP_ACK, PEER_ISN + 1, start.wrapping_add(window), &[]), window as u16));
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments.iter().map(|tcp| tcp.data.len()).sum::<usize>(), window as usize);
+        assert_eq!(segments[0].header.sequence.get(), start.wrapping_add(window));
+        assert!(tcp_handle(&mut tcpd, id).send_buf.is_empty());
+    }
+
+    #[test]
+    fn peer_reboot() {
+        let mut tcpd = tcpd();
+        let (listener, id) = accepted(&mut tcpd);
+        let snd_nxt = tcp_handle(&mut tcpd, id).snd_nxt;
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, snd_nxt, &[]));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Established);
+
+        // The rebooted peer connects again from the same port, with a new sequence number
+        let isn = PEER_ISN + 0x1000_0000;
+        deliver(&mut tcpd, from_peer(TCP_SYN, isn, 0, &[]));
+        let challenge = sent(&mut tcpd);
+        assert_eq!(challenge.len(), 1);
+        assert_eq!(challenge[0].header.flags.get() & 0xFFF, TCP_ACK);
+        assert_eq!(challenge[0].header.ack_num.get(), PEER_ISN + 1);
+        assert!(tcp_handle(&mut tcpd, listener).data.is_empty());
+
+        // It knows nothing of that connection and resets it where the ACK said
+        deliver(&mut tcpd, from_peer(TCP_RST, PEER_ISN + 1, 0, &[]));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Closed);
+        let mut buf = [0; 16];
+        assert_eq!(tcpd.read(id, &mut buf).err().map(|err| err.errno), Some(ECONNRESET));
+
+        // Its next SYN is a new connection
+        deliver(&mut tcpd, from_peer(TCP_SYN, isn, 0, &[]));
+        let id = tcpd.dup(listener, b"listen").ok().unwrap();
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::SynReceived);
+        assert_eq!(tcp_handle(&mut tcpd, id).rcv_nxt, isn + 1);
+    }
+
+    #[test]
+    fn write_segmented_by_mss() {
+        let mut tcpd = tcpd();
+        let id = established_with(&mut tcpd, &[2, 4, 0x03, 0xE8]);
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        // Taken whole, and sent in segments of the peer's MSS
+        assert_eq!(write(&mut tcpd, id, &[0; 2500]).ok(), Some(2500));
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments[0].data.len(), 1000);
+        assert_eq!(segments[1].data.len(), 1000);
+
+        let mut seq = start;
+        for tcp in segments.iter() {
+            assert!(tcp.data.len() <= 1000);
+            assert_eq!(tcp.header.sequence.get(), seq);
+            seq = seq.wrapping_add(tcp.data.len() as u32);
+        }
+        assert_eq!(tcp_handle(&mut tcpd, id).snd_nxt, seq);
+        assert_eq!(tcp_handle(&mut tcpd, id).unacked.len(), segments.len());
+    }
+
+    /// An `established` connection that we closed first and that is now in TimeWait. Our ACK
+    /// of the peer's FIN has been taken, as if it were lost
+    fn time_wait(tcpd: &mut Tcpd) -> usize {
+        let id = established(tcpd);
+        tcpd.close(id).unwrap();
+        let ack = tcp_handle(tcpd, id).snd_nxt;
+        deliver(tcpd, from_peer(TCP_ACK | TCP_FIN, PEER_ISN + 1, ack, &[]));
+        assert_eq!(tcp_handle(tcpd, id).state, State::TimeWait);
+        sent(tcpd);
+        id
+    }
+
+    #[test]
+    fn time_wait_fin_reacked() {
+        let mut tcpd = tcpd();
+        let id = time_wait(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        // A longer wait from here shows whether the timer was restarted
+        tcp_handle(&mut tcpd, id).time_wait = 2 * TIME_WAIT;
+        deliver(&mut tcpd, from_peer(TCP_ACK | TCP_FIN, PEER_ISN + 1, ack, &[]));
+        let answer = sent(&mut tcpd);
+        assert_eq!(answer.len(), 1);
+        assert_eq!(answer[0].header.flags.get() & 0xFFF, TCP_ACK);
+        assert_eq!(answer[0].header.ack_num.get(), PEER_ISN + 2);
+        let start = monotonic().ok().unwrap();
+
+        at(&mut tcpd, add_time(&start, &millis(TIME_WAIT)));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::TimeWait);
+        at(&mut tcpd, add_time(&start, &millis(2 * TIME_WAIT)));
+        assert!(! tcpd.handles.contains_key(&id));
+    }
+
+    #[test]
+    fn time_wait_data_reacked() {
+        let mut tcpd = tcpd();
+        let id = time_wait(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, ack, b"stale"));
+        let answer = sent(&mut tcpd);
+        assert_eq!(answer.len(), 1);
+        assert_eq!(answer[0].header.flags.get() & 0xFFF, TCP_ACK);
+        assert_eq!(answer[0].header.ack_num.get(), PEER_ISN + 2);
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::TimeWait);
+    }
+
+    #[test]
+    fn batch_coalesced() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let setting = tcpd.dup(id, b"nodelay").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &[1]).ok(), Some(1));
+        tcpd.close(setting).unwrap();
+
+        // Even without Nagle, writes from one batch of packets wait for the end of it
+        for chunk in [b"GET ", b"/ HT", b"TP/1"].iter() {
+            assert_eq!(tcpd.write(id, *chunk).ok(), Some(4));
+        }
+        assert!(sent(&mut tcpd).is_empty());
+
+        tcpd.flush().unwrap();
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].data, b"GET / HTTP/1");
+    }
+
+    #[test]
+    fn push_boundaries() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        let mut seq = PEER_ISN + 1;
+        for &(flags, data) in [(TCP_ACK, b"ab"), (TCP_ACK, b"cd"), (TCP_ACK | TCP_PSH, b"ef"), (TCP_ACK | TCP_PSH, b"gh")].iter() {
+            deliver(&mut tcpd, from_peer(flags, seq, ack, data));
+            seq += 2;
+        }
+
+        // By default a read takes one segment
+        let mut buf = [0; 16];
+        assert_eq!(tcpd.read(id, &mut buf).ok(), Some(2));
+        assert_eq!(&buf[.. 2], b"ab");
+
+        // With push boundaries it runs up to the end of the next pushed segment
+        let setting = tcpd.dup(id, b"push_boundaries").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &[1]).ok(), Some(1));
+        tcpd.close(setting).unwrap();
+        assert_eq!(tcpd.read(id, &mut buf).ok(), Some(4));
+        assert_eq!(&buf[.. 4], b"cdef");
+        assert_eq!(tcpd.read(id, &mut buf).ok(), Some(2));
+        assert_eq!(&buf[.. 2], b"gh");
+    }
+
+    /// Both sides start just short of 2^32, so every sequence number is past the wrap after the
+    /// first few hundred bytes. Tests are debug builds, where a plain `+` across it panics
+    #[test]
+    fn seq_wrap_transfer() {
+        const ISN: u32 = 0xFFFF_FF00;
+
+        let mut tcpd = tcpd();
+        let id = connect(&mut tcpd);
+        {
+            let handle = tcp_handle(&mut tcpd, id);
+            handle.snd_una = ISN;
+            handle.snd_nxt = ISN.wrapping_add(1);
+            handle.unacked[0].seq = ISN;
+        }
+        deliver(&mut tcpd, from_peer(TCP_SYN | TCP_ACK, ISN, ISN.wrapping_add(1), &[]));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Established);
+        sent(&mut tcpd);
+
+        let data: Vec<u8> = (0 .. 8192).map(|i| i as u8).collect();
+
+        // Out, acknowledging whatever has been sent each round
+        let mut written = 0;
+        let mut out = Vec::new();
+        let mut snd_nxt = ISN.wrapping_add(1);
+        while out.len() < data.len() {
+            written += write(&mut tcpd, id, &data[written ..]).unwrap_or(0);
+
+            let segments = sent(&mut tcpd);
+            assert!(! segments.is_empty());
+            for tcp in segments.iter() {
+                assert_eq!(tcp.header.sequence.get(), snd_nxt);
+                snd_nxt = snd_nxt.wrapping_add(tcp.data.len() as u32);
+                out.extend_from_slice(&tcp.data);
+            }
+
+            deliver(&mut tcpd, from_peer(TCP_ACK, ISN.wrapping_add(1), snd_nxt, &[]));
+        }
+        assert_eq!(out, data);
+        assert_eq!(snd_nxt, ISN.wrapping_add(1 + data.len() as u32));
+        assert!(tcp_handle(&mut tcpd, id).unacked.is_empty());
+
+        // In, reading each segment as it arrives
+        let mut seq = ISN.wrapping_add(1);
+        let mut read = Vec::new();
+        for chunk in data.chunks(512) {
+            deliver(&mut tcpd, from_peer(TCP_ACK | TCP_PSH, seq, snd_nxt, chunk));
+            seq = seq.wrapping_add(chunk.len() as u32);
+
+            let mut buf = [0; 1024];
+            let count = tcpd.read(id, &mut buf).ok().unwrap();
+            read.extend_from_slice(&buf[.. count]);
+        }
+        assert_eq!(read, data);
+        assert_eq!(tcp_handle(&mut tcpd, id).rcv_nxt, seq);
+    }
+
+    #[test]
+    fn paws() {
+        let mut tcpd = tcpd();
+        let id = established_with(&mut tcpd, &timestamps_option(7777, 0));
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        deliver(&mut tcpd, with_options(from_peer(TCP_ACK, PEER_ISN + 1, ack, b"new"), &timestamps_option(8888, 0)));
+        sent(&mut tcpd);
+
+        // An old duplicate from before the sequence numbers wrapped would fit the window, but its
+        // timestamp gives it away
+        deliver(&mut tcpd, with_options(from_peer(TCP_ACK, PEER_ISN + 4, ack, b"old"), &timestamps_option(7000, 0)));
+        let answer = sent(&mut tcpd);
+        assert_eq!(answer.len(), 1);
+        assert_eq!(answer[0].header.ack_num.get(), PEER_ISN + 4);
+        assert_eq!(tcp_handle(&mut tcpd, id).rcv_nxt, PEER_ISN + 4);
+        assert_eq!(tcp_handle(&mut tcpd, id).ts_recent, 8888);
+        assert_eq!(read_all(&mut tcpd, id), b"new");
+    }
+
+    #[test]
+    fn reset_wakes_blocked_reader() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        deliver(&mut tcpd, stream_from_peer(ack, 0, 100));
+        assert_eq!(read_all(&mut tcpd, id).len(), 100);
+
+        // The reader waits for more, queued as scheme_event does
+        let mut buf = [0; 16];
+        assert_eq!(tcpd.read(id, &mut buf).err().map(|err| err.errno), Some(EWOULDBLOCK));
+        tcp_handle(&mut tcpd, id).todo_read.push_back((None, Packet { id: 7, a: syscall::number::SYS_READ, b: id, ..Packet::default() }));
+
+        deliver(&mut tcpd, from_peer(TCP_RST | TCP_ACK, PEER_ISN + 101, ack, &[]));
+        let answers = answers(&mut tcpd);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].id, 7);
+        assert_eq!(answers[0].a, (-ECONNRESET) as usize);
+
+        // The handle stays, at the end of its stream and no longer writable
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Closed);
+        assert_eq!(tcpd.read(id, &mut buf).ok(), Some(0));
+        assert_eq!(tcpd.write(id, b"more").err().map(|err| err.errno), Some(EPIPE));
+    }
+
+    #[test]
+    fn retransmit_give_up() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        assert_eq!(write(&mut tcpd, id, b"lost").ok(), Some(4));
+        let handle = tcp_handle(&mut tcpd, id);
+        handle.retries = handle.max_retries;
+        sent(&mut tcpd);
+
+        tcpd.retransmit(id, &monotonic().ok().unwrap()).unwrap();
+
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].header.flags.get() & 0xFFF, TCP_RST | TCP_ACK);
+
+        // The handle stays until the application closes it, and its next call says why
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Closed);
+        assert_eq!(tcpd.write(id, b"more").err().map(|err| err.errno), Some(ETIMEDOUT));
+    }
+
+    #[test]
+    fn reset_answers_lingering_close() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        assert_eq!(write(&mut tcpd, id, b"unacknowledged").ok(), Some(14));
+        tcp_handle(&mut tcpd, id).linger = Linger { enabled: 1, seconds: 1 };
+        sent(&mut tcpd);
+
+        // The close waits for the data to be acknowledged, queued as scheme_event does
+        assert_eq!(tcpd.close(id).err().map(|err| err.errno), Some(EWOULDBLOCK));
+        let (snd_nxt, rcv_nxt) = {
+            let handle = tcp_handle(&mut tcpd, id);
+            handle.todo_close.push_back(Packet { id: 7, a: syscall::number::SYS_CLOSE, b: id, ..Packet::default() });
+            (handle.snd_nxt, handle.rcv_nxt)
+        };
+
+        deliver(&mut tcpd, from_peer(TCP_RST | TCP_ACK, rcv_nxt, snd_nxt, &[]));
+        let answers = answers(&mut tcpd);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].id, 7);
+        assert_eq!(answers[0].a, (-ECONNRESET) as usize);
+        assert!(! tcpd.handles.contains_key(&id));
+    }
+
+    #[test]
+    fn reset_refuses_connect() {
+        let mut tcpd = tcpd();
+        let id = connect(&mut tcpd);
+        let snd_nxt = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        // Nobody listens on the port
+        deliver(&mut tcpd, from_peer(TCP_RST | TCP_ACK, 0, snd_nxt, &[]));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Closed);
+        assert_eq!(tcpd.timers.expire(&TimeSpec { tv_sec: i64::MAX, tv_nsec: 0 }), Vec::new());
+
+        // The error setting reports it once
+        let setting = tcpd.dup(id, b"error").ok().unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(tcpd.read(setting, &mut buf).ok(), Some(mem::size_of::<usize>()));
+        assert_eq!(usize::from_ne_bytes(buf), ECONNREFUSED as usize);
+        assert_eq!(tcpd.read(setting, &mut buf).ok(), Some(mem::size_of::<usize>()));
+        assert_eq!(usize::from_ne_bytes(buf), 0);
+    }
+
+    #[test]
+    fn urgent_sent() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let seq = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        let oob = tcpd.dup(id, b"oob").ok().unwrap();
+        assert_eq!(tcpd.write(oob, b"!").ok(), Some(1));
+        tcpd.flush().unwrap();
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].data, b"!");
+        assert_eq!(segments[0].header.flags.get() & TCP_URG, TCP_URG);
+        assert_eq!(segments[0].header.urgent_pointer.get(), 1);
+
+        // Once the peer has the urgent byte, nothing else is marked
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, seq.wrapping_add(1), &[]));
+        assert_eq!(write(&mut tcpd, id, b"normal").ok(), Some(6));
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments[0].header.flags.get() & TCP_URG, 0);
+    }
+
+    #[test]
+    fn urgent_received() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        let (ip, mut tcp) = from_peer(TCP_ACK | TCP_URG, PEER_ISN + 1, ack, b"ab!");
+        tcp.header.urgent_pointer = n16::new(3);
+        deliver(&mut tcpd, (ip, tcp));
+        assert_eq!(tcp_handle(&mut tcpd, id).rcv_nxt, PEER_ISN + 4);
+
+        // The urgent byte is only in the oob path, the rest of the stream reads as usual
+        assert_eq!(read_all(&mut tcpd, id), b"ab");
+        let oob = tcpd.dup(id, b"oob").ok().unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(tcpd.read(oob, &mut buf).ok(), Some(1));
+        assert_eq!(buf[0], b'!');
+        assert_eq!(tcpd.read(oob, &mut buf).ok(), Some(0));
+    }
+
+    #[test]
+    fn path_mtu() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+        assert_eq!(write(&mut tcpd, id, &[0; 1000]).ok(), Some(1000));
+        let packets = sent_ip(&mut tcpd);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].0.header.flags_fragment.get() & IP_DF, IP_DF);
+        assert_eq!(packets[0].1.data.len(), DEFAULT_MSS as usize);
+
+        let mtu = tcpd.dup(id, b"mtu").ok().unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(tcpd.read(mtu, &mut buf).ok(), Some(8));
+        assert_eq!(usize::from_ne_bytes(buf), DEFAULT_MSS as usize + 40);
+        assert_eq!(tcpd.write(mtu, &(MTU_MIN - 1).to_ne_bytes()).err().map(|err| err.errno), Some(EINVAL));
+
+        // The path turns out to carry less, so the segment in flight goes again in a smaller piece
+        assert_eq!(tcpd.write(mtu, &440usize.to_ne_bytes()).ok(), Some(8));
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].header.sequence.get(), start);
+        assert_eq!(segments[0].data.len(), 400);
+
+        let handle = tcp_handle(&mut tcpd, id);
+        assert_eq!(handle.remote_mss, 400);
+        assert_eq!(handle.unacked.iter().map(|segment| segment.data.len()).collect::<Vec<usize>>(), vec![400, DEFAULT_MSS as usize - 400]);
+        assert_eq!(handle.retries, 0);
+    }
+
+    #[test]
+    fn reset_exact() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let snd_nxt = tcp_handle(&mut tcpd, id).snd_nxt;
+
+        deliver(&mut tcpd, from_peer(TCP_RST | TCP_ACK, PEER_ISN + 1, snd_nxt, &[]));
+
+        // The application still holds the handle, so it is kept to report the reset
+        assert!(sent(&mut tcpd).is_empty());
+        let handle = tcp_handle(&mut tcpd, id);
+        assert_eq!(handle.state, State::Closed);
+        assert_eq!(handle.error, Some(ECONNRESET));
+    }
+
+    #[test]
+    fn reset_in_window() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let (rcv_nxt, window) = {
+            let handle = tcp_handle(&mut tcpd, id);
+            (handle.rcv_nxt, handle.window())
+        };
+
+        for &seq in [rcv_nxt + 1, rcv_nxt + 1000, rcv_nxt + window - 1].iter() {
+            deliver(&mut tcpd, from_peer(TCP_RST, seq, 0, &[]));
+
+            // A challenge ACK restating where we are, which the real peer answers with an exact reset
+            let segments = sent(&mut tcpd);
+            assert_eq!(segments.len(), 1, "seq {}", seq);
+            assert_eq!(segments[0].header.flags.get() & 0xFFF, TCP_ACK);
+            assert_eq!(segments[0].header.ack_num.get(), rcv_nxt);
+
+            let handle = tcp_handle(&mut tcpd, id);
+            assert_eq!(handle.state, State::Established);
+            assert_eq!(handle.error, None);
+        }
+    }
+
+    #[test]
+    fn reset_out_of_window() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let (rcv_nxt, window) = {
+            let handle = tcp_handle(&mut tcpd, id);
+            (handle.rcv_nxt, handle.window())
         };
 
-        if let Handle::Tcp(ref mut handle) = *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
-            let set_timeout = |timeout: &mut Option<TimeSpec>, buf: &[u8]| -> Result<usize> {
-                if buf.len() >= mem::size_of::<TimeSpec>() {
-                    let mut timespec = TimeSpec::default();
-                    let count = timespec.deref_mut().write(buf).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
-                    *timeout = Some(timespec);
-                    Ok(count)
-                } else {
-                    *timeout = None;
-                    Ok(0)
-                }
-            };
+        for &seq in [rcv_nxt.wrapping_sub(1), rcv_nxt + window, rcv_nxt + 0x8000_0000].iter() {
+            deliver(&mut tcpd, from_peer(TCP_RST, seq, 0, &[]));
 
-            match kind {
-                SettingKind::Ttl => {
-                    if let Some(ttl) = buf.get(0) {
-                        handle.ttl = *ttl;
-                        Ok(1)
-                    } else {
-                        Ok(0)
-                    }
-                },
-                SettingKind::ReadTimeout => {
-                    set_timeout(&mut handle.read_timeout, buf)
-                },
-                SettingKind::WriteTimeout => {
-                    set_timeout(&mut handle.write_timeout, buf)
-                }
-            }
-        } else {
-            Err(Error::new(EBADF))
+            assert!(sent(&mut tcpd).is_empty(), "seq {}", seq);
+            let handle = tcp_handle(&mut tcpd, id);
+            assert_eq!(handle.state, State::Established);
+            assert_eq!(handle.error, None);
         }
+        assert_eq!(tcpd.window_resets, 3);
     }
 
-    fn fcntl(&mut self, file: usize, cmd: usize, arg: usize) -> Result<usize> {
-        match *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
-            Handle::Empty(ref mut handle) => {
-                match cmd {
-                    F_GETFL => Ok(handle.flags),
-                    F_SETFL => {
-                        handle.flags = arg & ! O_ACCMODE;
-                        Ok(0)
-                    },
-                    _ => Err(Error::new(EINVAL))
-                }
-            },
-            Handle::Tcp(ref mut handle) => {
-                match cmd {
-                    F_GETFL => Ok(handle.flags),
-                    F_SETFL => {
-                        handle.flags = arg & ! O_ACCMODE;
-                        Ok(0)
-                    },
-                    _ => Err(Error::new(EINVAL))
-                }
-            }
-            _ => Err(Error::new(EBADF))
+    #[test]
+    fn time_wait_default() {
+        let mut tcpd = tcpd();
+
+        // Only root sets it
+        let user = tcpd.open(b"", O_RDWR, 1000, 1000).ok().unwrap();
+        let setting = tcpd.dup(user, b"time_wait").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &millis(1000)).err().map(|err| err.errno), Some(EACCES));
+
+        let root = tcpd.open(b"", O_RDWR, 0, 0).ok().unwrap();
+        let setting = tcpd.dup(root, b"time_wait").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &millis(1000)).ok(), Some(mem::size_of::<TimeSpec>()));
+        let mut timespec = TimeSpec::default();
+        assert_eq!(tcpd.read(setting, &mut timespec).ok(), Some(mem::size_of::<TimeSpec>()));
+        assert_eq!(to_millis(&timespec), 1000);
+
+        // A retransmitted FIN is still answered within the shorter wait, which it restarts
+        let id = time_wait(&mut tcpd);
+        assert_eq!(tcp_handle(&mut tcpd, id).time_wait, 1000);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        deliver(&mut tcpd, from_peer(TCP_ACK | TCP_FIN, PEER_ISN + 1, ack, &[]));
+        assert_eq!(sent(&mut tcpd).len(), 1);
+        let start = monotonic().ok().unwrap();
+
+        at(&mut tcpd, add_time(&start, &millis(1000)));
+        assert!(! tcpd.handles.contains_key(&id));
+    }
+
+    #[test]
+    fn maxseg() {
+        let mut tcpd = tcpd();
+
+        // Set before connecting, it is what the SYN advertises, and never below the minimum
+        let id = tcpd.open(b"", O_RDWR, 0, 0).ok().unwrap();
+        let setting = tcpd.dup(id, b"maxseg").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &10usize.to_ne_bytes()).ok(), Some(8));
+        let mut buf = [0; 8];
+        assert_eq!(tcpd.read(setting, &mut buf).ok(), Some(8));
+        assert_eq!(usize::from_ne_bytes(buf), MSS_MIN as usize);
+        assert_eq!(tcpd.write(setting, &500usize.to_ne_bytes()).ok(), Some(8));
+
+        let conn = tcpd.dup(id, b"10.0.0.2:80/10.0.0.1:5000").ok().unwrap();
+        let syn = sent(&mut tcpd);
+        assert_eq!(syn.len(), 1);
+        assert_eq!(parse_mss(&syn[0].options), Some(500));
+
+        // The peer offers more, but we keep to what we asked for
+        let seq = tcp_handle(&mut tcpd, conn).snd_nxt;
+        deliver(&mut tcpd, with_options(from_peer(TCP_SYN | TCP_ACK, PEER_ISN, seq, &[]), &mss_option(1460)));
+        assert_eq!(tcp_handle(&mut tcpd, conn).remote_mss, 500);
+        sent(&mut tcpd);
+
+        // Lowered on the open connection, later writes go in smaller segments
+        let setting = tcpd.dup(conn, b"maxseg").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &200usize.to_ne_bytes()).ok(), Some(8));
+        assert_eq!(tcpd.read(setting, &mut buf).ok(), Some(8));
+        assert_eq!(usize::from_ne_bytes(buf), 200);
+        assert_eq!(write(&mut tcpd, conn, &[0; 300]).ok(), Some(300));
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments[0].data.len(), 200);
+    }
+
+    #[test]
+    fn reset_unknown_segments() {
+        let mut tcpd = tcpd();
+
+        // An ACK for a connection we have forgotten is reset from where it says we were
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN, 0x5000, b"late"));
+        let reset = sent(&mut tcpd);
+        assert_eq!(reset.len(), 1);
+        assert_eq!(reset[0].header.flags.get() & 0xFFF, TCP_RST);
+        assert_eq!(reset[0].header.sequence.get(), 0x5000);
+
+        // A connect to a port nobody listens on is refused, acknowledging the SYN
+        deliver(&mut tcpd, from_peer(TCP_SYN, PEER_ISN, 0, &[]));
+        let reset = sent(&mut tcpd);
+        assert_eq!(reset.len(), 1);
+        assert_eq!(reset[0].header.flags.get() & 0xFFF, TCP_RST | TCP_ACK);
+        assert_eq!(reset[0].header.sequence.get(), 0);
+        assert_eq!(reset[0].header.ack_num.get(), PEER_ISN + 1);
+
+        // A RST is never answered
+        deliver(&mut tcpd, from_peer(TCP_RST, PEER_ISN, 0, &[]));
+        assert!(sent(&mut tcpd).is_empty());
+    }
+
+    #[test]
+    fn close_unread_resets() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let snd_nxt = tcp_handle(&mut tcpd, id).snd_nxt;
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, snd_nxt, b"never read"));
+        sent(&mut tcpd);
+
+        // A FIN would tell the peer its data was delivered
+        tcpd.close(id).unwrap();
+        let reset = sent(&mut tcpd);
+        assert_eq!(reset.len(), 1);
+        assert_eq!(reset[0].header.flags.get() & 0xFFF, TCP_RST | TCP_ACK);
+        assert_eq!(reset[0].header.sequence.get(), snd_nxt);
+        assert_eq!(reset[0].header.ack_num.get(), PEER_ISN + 11);
+        assert!(! tcpd.handles.contains_key(&id));
+
+        // Data only waiting in reassembly is just as undelivered
+        let id = established(&mut tcpd);
+        let snd_nxt = tcp_handle(&mut tcpd, id).snd_nxt;
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 5, snd_nxt, b"late"));
+        sent(&mut tcpd);
+        assert!(tcp_handle(&mut tcpd, id).data.is_empty());
+        tcpd.close(id).unwrap();
+        let reset = sent(&mut tcpd);
+        assert_eq!(reset.len(), 1);
+        assert_eq!(reset[0].header.flags.get() & 0xFFF, TCP_RST | TCP_ACK);
+        assert_eq!(reset[0].header.ack_num.get(), PEER_ISN + 1);
+        assert!(! tcpd.handles.contains_key(&id));
+    }
+
+    #[test]
+    fn closed_connect_reset() {
+        let mut client = tcpd();
+        let mut server = tcpd();
+        let listener = server.open(b"/10.0.0.2:80", O_RDWR, 0, 0).ok().unwrap();
+        let start = monotonic().ok().unwrap();
+        let a = client.open(b"10.0.0.2:80/10.0.0.1:5000", O_RDWR, 0, 0).ok().unwrap();
+        exchange(&mut client, &mut server);
+
+        // Given up on before the slow accepter gets to the SYN, along with its retransmission
+        at(&mut client, add_time(&start, &millis(10)));
+        client.close(a).unwrap();
+        assert!(client.handles.is_empty());
+        at(&mut client, add_time(&start, &millis(SYN_RTO_INITIAL * 4)));
+        assert!(sent(&mut client).is_empty());
+
+        // The SYN-ACK that comes later is reset, which frees the server's connection
+        let b = server.dup(listener, b"listen").ok().unwrap();
+        let syn_ack = sent_ip(&mut server);
+        assert_eq!(syn_ack.len(), 1);
+        deliver(&mut client, syn_ack[0].clone());
+        let reset = sent_ip(&mut client);
+        assert_eq!(reset.len(), 1);
+        assert_eq!(reset[0].1.header.flags.get() & 0xFFF, TCP_RST);
+        assert_eq!(reset[0].1.header.sequence.get(), syn_ack[0].1.header.ack_num.get());
+
+        deliver(&mut server, reset[0].clone());
+        assert_eq!(tcp_handle(&mut server, b).state, State::Closed);
+    }
+
+    #[test]
+    fn state_path() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let state = tcpd.dup(id, b"state").ok().unwrap();
+        let mut buf = [0; 16];
+        let len = tcpd.read(state, &mut buf).ok().unwrap();
+        assert_eq!(&buf[.. len], b"established");
+
+        // Read again after the connection has moved on
+        tcpd.close(id).unwrap();
+        let len = tcpd.read(state, &mut buf).ok().unwrap();
+        assert_eq!(&buf[.. len], b"fin_wait1");
+    }
+
+    #[test]
+    fn flags() {
+        let (_, mut tcp) = from_peer(0, 0, 0, &[]);
+        for &(flags, data, ok) in [
+            (TCP_SYN, false, true),
+            (TCP_SYN | TCP_ACK, false, true),
+            (TCP_SYN | TCP_FIN, false, false),
+            (TCP_SYN | TCP_RST | TCP_ACK, false, false),
+            (TCP_RST, false, true),
+            (TCP_RST | TCP_FIN, false, false),
+            (TCP_RST | TCP_FIN | TCP_ACK, false, true),
+            (TCP_FIN | TCP_ACK, false, true),
+            (TCP_PSH, false, true),
+            (0, false, false),
+            (0, true, true),
+        ].iter() {
+            tcp.header.flags.set(0x5000 | flags);
+            tcp.data = if data { vec![0] } else { Vec::new() };
+            assert_eq!(flags_ok(&tcp), ok, "flags {:x}", flags);
         }
+
+        // Dropped before anything answers them
+        let mut tcpd = tcpd();
+        deliver(&mut tcpd, from_peer(TCP_SYN | TCP_FIN, PEER_ISN, 0, &[]));
+        assert!(sent(&mut tcpd).is_empty());
+        assert_eq!(tcpd.flag_errors, 1);
     }
 
-    fn fevent(&mut self, file: usize, flags: usize) -> Result<usize> {
-        if let Handle::Tcp(ref mut handle) = *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
-            handle.events = flags;
-            Ok(file)
-        } else {
-            Err(Error::new(EBADF))
+    #[test]
+    fn shutdown_write() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let setting = tcpd.dup(id, b"shutdown").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &[SHUT_WR]).ok(), Some(1));
+        tcpd.close(setting).unwrap();
+        let fin = sent(&mut tcpd);
+        assert_eq!(fin.len(), 1);
+        assert_eq!(fin[0].header.flags.get() & 0xFFF, TCP_ACK | TCP_FIN);
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::FinWait1);
+
+        // Writing is over, but the handle is still there to read from
+        assert_eq!(write(&mut tcpd, id, b"more").err().map(|err| err.errno), Some(EPIPE));
+        let mut buf = [0; 64];
+        let count = tcpd.fpath(id, &mut buf).ok().unwrap();
+        assert_eq!(&buf[.. count], b"tcp:10.0.0.2:80/10.0.0.1:5000");
+
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, ack, b"response"));
+        assert_eq!(read_all(&mut tcpd, id), b"response");
+        deliver(&mut tcpd, from_peer(TCP_ACK | TCP_FIN, PEER_ISN + 9, ack, &[]));
+        assert_eq!(tcpd.read(id, &mut buf).ok(), Some(0));
+
+        tcpd.close(id).unwrap();
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::TimeWait);
+    }
+
+    #[test]
+    fn half_open_limit() {
+        let mut tcpd = tcpd();
+        let listener = tcpd.open(b"/10.0.0.1:5000", O_RDWR, 0, 0).ok().unwrap();
+        let backlog = tcpd.dup(listener, b"backlog").ok().unwrap();
+        assert_eq!(tcpd.write(backlog, &(HALF_OPEN_LIMIT * 2).to_ne_bytes()).ok(), Some(8));
+
+        let syn = |port: u16| {
+            let (ip, mut tcp) = from_peer(TCP_SYN, PEER_ISN, 0, &[]);
+            tcp.header.src = n16::new(port);
+            (ip, tcp)
+        };
+        for port in 0 .. HALF_OPEN_LIMIT as u16 {
+            deliver(&mut tcpd, syn(1000 + port));
         }
+        assert_eq!(tcpd.half_open, HALF_OPEN_LIMIT);
+
+        // Dropped at the limit, although the backlog has room
+        deliver(&mut tcpd, syn(100));
+        assert_eq!(tcp_handle(&mut tcpd, listener).data.len(), HALF_OPEN_LIMIT);
+
+        // Still half open once accepted, until the handshake completes
+        let id = tcpd.dup(listener, b"listen").ok().unwrap();
+        assert_eq!(tcpd.half_open, HALF_OPEN_LIMIT);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        let (ip, mut tcp) = from_peer(TCP_ACK, PEER_ISN + 1, ack, &[]);
+        tcp.header.src = n16::new(1000);
+        deliver(&mut tcpd, (ip, tcp));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Established);
+        assert_eq!(tcpd.half_open, HALF_OPEN_LIMIT - 1);
+
+        deliver(&mut tcpd, syn(100));
+        assert_eq!(tcpd.half_open, HALF_OPEN_LIMIT);
+
+        // The queue goes with the listener
+        tcpd.close(backlog).unwrap();
+        tcpd.close(listener).unwrap();
+        assert_eq!(tcpd.half_open, 0);
     }
 
-    fn fpath(&mut self, file: usize, buf: &mut [u8]) -> Result<usize> {
-        if let Handle::Tcp(ref mut handle) = *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
-            let path_string = format!("tcp:{}:{}/{}:{}", handle.remote.0.to_string(), handle.remote.1, handle.local.0.to_string(), handle.local.1);
-            let path = path_string.as_bytes();
+    #[test]
+    fn shutdown_read() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let setting = tcpd.dup(id, b"shutdown").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &[SHUT_RD]).ok(), Some(1));
+        tcpd.close(setting).unwrap();
+        let window = tcp_handle(&mut tcpd, id).window();
 
-            let mut i = 0;
-            while i < buf.len() && i < path.len() {
-                buf[i] = path[i];
-                i += 1;
-            }
+        // A peer that keeps sending is acknowledged as before, but nothing piles up
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        let mut seq = PEER_ISN + 1;
+        for _ in 0 .. 1000 {
+            deliver(&mut tcpd, from_peer(TCP_ACK | TCP_PSH, seq, ack, &[0; 1000]));
+            seq = seq.wrapping_add(1000);
 
-            Ok(i)
-        } else {
-            Err(Error::new(EBADF))
+            let handle = tcp_handle(&mut tcpd, id);
+            assert_eq!(handle.rcv_nxt, seq);
+            assert!(handle.data.is_empty());
+            assert_eq!(handle.window(), window);
         }
+        assert_eq!(sent(&mut tcpd).last().map(|tcp| tcp.header.ack_num.get()), Some(seq));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Established);
+
+        let mut buf = [0; 16];
+        assert_eq!(tcpd.read(id, &mut buf).ok(), Some(0));
     }
 
-    fn fsync(&mut self, file: usize) -> Result<usize> {
-        let _handle = self.handles.get(&file).ok_or(Error::new(EBADF))?;
+    #[test]
+    fn close_fails_blocked_write() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        tcp_handle(&mut tcpd, id).todo_write.push_back((None, Packet { id: 7, a: syscall::number::SYS_WRITE, b: id, ..Packet::default() }));
 
-        Ok(0)
+        assert_eq!(tcpd.close(id).ok(), Some(0));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::FinWait1);
+
+        let answers = answers(&mut tcpd);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].id, 7);
+        assert_eq!(answers[0].a, (-EPIPE) as usize);
     }
 
-    fn close(&mut self, file: usize) -> Result<usize> {
-        let closed = {
-            if let Handle::Tcp(ref mut handle) = *self.handles.get_mut(&file).ok_or(Error::new(EBADF))? {
-                handle.data.clear();
+    #[test]
+    fn write_states() {
+        let mut tcpd = tcpd();
 
-                match handle.state {
-                    State::SynReceived | State::Established => {
-                        handle.state = State::FinWait1;
+        // Before the handshake completes a write waits, or says so when it must not block
+        let id = connect(&mut tcpd);
+        assert_eq!(tcpd.write(id, b"data").err().map(|err| err.errno), Some(EWOULDBLOCK));
+        tcpd.close(id).unwrap();
+        let id = tcpd.open(b"10.0.0.2:80/10.0.0.1:5000", O_RDWR | O_NONBLOCK, 0, 0).ok().unwrap();
+        assert_eq!(tcpd.write(id, b"data").err().map(|err| err.errno), Some(EINPROGRESS));
+        tcpd.close(id).unwrap();
+        sent(&mut tcpd);
 
-                        let tcp = handle.create_tcp(TCP_FIN | TCP_ACK, Vec::new());
-                        let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                        self.tcp_file.write(&ip.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+        // Only the peer has closed in CloseWait, so the data still goes out
+        let id = established(&mut tcpd);
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        deliver(&mut tcpd, from_peer(TCP_ACK | TCP_FIN, PEER_ISN + 1, ack, &[]));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::CloseWait);
+        sent(&mut tcpd);
+        assert_eq!(write(&mut tcpd, id, b"data").ok(), Some(4));
+        assert_eq!(sent(&mut tcpd)[0].data, b"data");
+        tcpd.close(id).unwrap();
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 2, ack + 5, &[]));
+        assert!(tcpd.handles.is_empty());
 
-                        handle.seq += 1;
+        // Once our FIN is sent, and until the handle goes, writes fail
+        let id = established(&mut tcpd);
+        let setting = tcpd.dup(id, b"shutdown").ok().unwrap();
+        assert_eq!(tcpd.write(setting, &[SHUT_WR]).ok(), Some(1));
+        tcpd.close(setting).unwrap();
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::FinWait1);
+        assert_eq!(tcpd.write(id, b"data").err().map(|err| err.errno), Some(EPIPE));
 
-                        false
-                    },
-                    State::CloseWait => {
-                        handle.state = State::LastAck;
+        let ack = tcp_handle(&mut tcpd, id).snd_nxt;
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, ack, &[]));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::FinWait2);
+        assert_eq!(tcpd.write(id, b"data").err().map(|err| err.errno), Some(EPIPE));
 
-                        let tcp = handle.create_tcp(TCP_FIN | TCP_ACK, Vec::new());
-                        let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                        self.tcp_file.write(&ip.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+        deliver(&mut tcpd, from_peer(TCP_ACK | TCP_FIN, PEER_ISN + 1, ack, &[]));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::TimeWait);
+        assert_eq!(tcpd.write(id, b"data").err().map(|err| err.errno), Some(EPIPE));
 
-                        handle.seq += 1;
+        at(&mut tcpd, add_time(&monotonic().ok().unwrap(), &millis(TIME_WAIT)));
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::Closed);
+        assert_eq!(tcpd.write(id, b"data").err().map(|err| err.errno), Some(EPIPE));
+        assert!(sent(&mut tcpd).iter().all(|tcp| tcp.data.is_empty()));
+    }
 
-                        false
-                    },
-                    _ => true
-                }
-            } else {
-                true
-            }
+    #[test]
+    fn syn_cookies() {
+        let mut tcpd = tcpd();
+        let listener = tcpd.open(b"/10.0.0.1:5000", O_RDWR, 0, 0).ok().unwrap();
+        let backlog = tcpd.dup(listener, b"backlog").ok().unwrap();
+        assert_eq!(tcpd.write(backlog, &(HALF_OPEN_LIMIT * 2).to_ne_bytes()).ok(), Some(8));
+        tcpd.close(backlog).unwrap();
+
+        let from = |port: u16, segment: (Ipv4, Tcp)| {
+            let (ip, mut tcp) = segment;
+            tcp.header.src = n16::new(port);
+            (ip, tcp)
         };
+        for port in 0 .. HALF_OPEN_LIMIT as u16 {
+            deliver(&mut tcpd, from(1000 + port, from_peer(TCP_SYN, PEER_ISN, 0, &[])));
+        }
+        sent(&mut tcpd);
 
-        if closed {
-            if let Handle::Tcp(handle) = self.handles.remove(&file).ok_or(Error::new(EBADF))? {
-                let remove = if let Some(mut port) = self.ports.get_mut(&handle.local.1) {
-                    *port = *port + 1;
-                    *port == 0
-                } else {
-                    false
-                };
+        // Past the limit a SYN is answered, but nothing is kept for it
+        deliver(&mut tcpd, from(100, with_options(from_peer(TCP_SYN, PEER_ISN, 0, &[]), &mss_option(1400))));
+        let syn_ack = sent(&mut tcpd);
+        assert_eq!(syn_ack.len(), 1);
+        assert_eq!(syn_ack[0].header.flags.get() & 0xFFF, TCP_SYN | TCP_ACK);
+        assert_eq!(syn_ack[0].header.ack_num.get(), PEER_ISN + 1);
+        assert_eq!(parse_mss(&syn_ack[0].options), Some(1400));
+        assert_eq!(tcpd.half_open, HALF_OPEN_LIMIT);
+        let cookie = syn_ack[0].header.sequence.get();
 
-                if remove {
-                    self.ports.remove(&handle.local.1);
-                }
-            }
+        // An ACK with the wrong cookie is reset, not queued
+        deliver(&mut tcpd, from(100, from_peer(TCP_ACK, PEER_ISN + 1, cookie.wrapping_add(2), &[])));
+        assert_eq!(sent(&mut tcpd)[0].header.flags.get() & 0xFFF, TCP_RST);
+        assert_eq!(tcp_handle(&mut tcpd, listener).data.len(), HALF_OPEN_LIMIT);
+
+        // The right one finishes the handshake, and is accepted after everything queued before it
+        deliver(&mut tcpd, from(100, from_peer(TCP_ACK, PEER_ISN + 1, cookie.wrapping_add(1), &[])));
+        assert!(sent(&mut tcpd).is_empty());
+        assert_eq!(tcp_handle(&mut tcpd, listener).data.len(), HALF_OPEN_LIMIT + 1);
+
+        let mut id = 0;
+        for _ in 0 ..= HALF_OPEN_LIMIT {
+            id = tcpd.dup(listener, b"listen").ok().unwrap();
         }
+        let handle = tcp_handle(&mut tcpd, id);
+        assert_eq!(handle.state, State::Established);
+        assert_eq!(handle.remote.1, 100);
+        assert_eq!(handle.snd_nxt, cookie.wrapping_add(1));
+        assert_eq!(handle.rcv_nxt, PEER_ISN + 1);
+        assert_eq!(handle.remote_mss, 1400);
+        assert_eq!(tcpd.half_open, HALF_OPEN_LIMIT);
+    }
 
-        Ok(0)
+    #[test]
+    fn initial_sequence_clock() {
+        let local = (Ipv4Addr::from_str("10.0.0.1"), 5000);
+        let remote = (Ipv4Addr::from_str("10.0.0.2"), 80);
+        let time = TimeSpec { tv_sec: 100, tv_nsec: 0 };
+
+        // The same addresses move on with the clock, so a new connection starts past the old one
+        let isn = initial_sequence(7, local, remote, &time);
+        assert_eq!(initial_sequence(7, local, remote, &add_time(&time, &millis(1))), isn.wrapping_add(250));
+
+        // Other addresses, or another key, start somewhere else entirely
+        assert_ne!(initial_sequence(7, local, (remote.0, 81), &time), isn);
+        assert_ne!(initial_sequence(8, local, remote, &time), isn);
     }
-}
 
-fn daemon(scheme_fd: usize, tcp_fd: usize, time_fd: usize) {
-    let scheme_file = unsafe { File::from_raw_fd(scheme_fd) };
-    let tcp_file = unsafe { File::from_raw_fd(tcp_fd) };
-    let time_file = unsafe { File::from_raw_fd(time_fd) };
+    /// Set the linger setting of `id` through its setting handle, after writing data that the
+    /// peer has not acknowledged yet
+    fn linger(tcpd: &mut Tcpd, id: usize, linger: Linger) {
+        assert_eq!(write(tcpd, id, b"unacknowledged").ok(), Some(14));
+        sent(tcpd);
 
-    let tcpd = Rc::new(RefCell::new(Tcpd::new(scheme_file, tcp_file, time_file)));
+        let setting = tcpd.dup(id, b"linger").ok().unwrap();
+        let bytes = unsafe { slice::from_raw_parts(&linger as *const Linger as *const u8, mem::size_of::<Linger>()) };
+        assert_eq!(tcpd.write(setting, bytes).ok(), Some(mem::size_of::<Linger>()));
+        let mut buf = [0; 8];
+        assert_eq!(tcpd.read(setting, &mut buf).ok(), Some(mem::size_of::<Linger>()));
+        assert_eq!(&buf[..], bytes);
+        tcpd.close(setting).unwrap();
+    }
 
-    let mut event_queue = EventQueue::<()>::new().expect("tcpd: failed to create event queue");
+    #[test]
+    fn linger_off() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        linger(&mut tcpd, id, Linger::default());
 
-    let time_tcpd = tcpd.clone();
-    event_queue.add(time_fd, move |_count: usize| -> io::Result<Option<()>> {
-        time_tcpd.borrow_mut().time_event()?;
-        Ok(None)
-    }).expect("tcpd: failed to listen to events on time:");
+        // Close returns at once, and the FIN follows the data
+        assert_eq!(tcpd.close(id).ok(), Some(0));
+        let fin = sent(&mut tcpd);
+        assert_eq!(fin.len(), 1);
+        assert_eq!(fin[0].header.flags.get() & 0xFFF, TCP_ACK | TCP_FIN);
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::FinWait1);
+        assert_eq!(tcp_handle(&mut tcpd, id).unacked.len(), 2);
+    }
 
-    let tcp_tcpd = tcpd.clone();
-    event_queue.add(tcp_fd, move |_count: usize| -> io::Result<Option<()>> {
-        tcp_tcpd.borrow_mut().tcp_event()?;
-        Ok(None)
-    }).expect("tcpd: failed to listen to events on ip:6");
+    #[test]
+    fn linger_zero() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        linger(&mut tcpd, id, Linger { enabled: 1, seconds: 0 });
+        let (snd_nxt, rcv_nxt) = (tcp_handle(&mut tcpd, id).snd_nxt, tcp_handle(&mut tcpd, id).rcv_nxt);
 
-    event_queue.add(scheme_fd, move |_count: usize| -> io::Result<Option<()>> {
-        tcpd.borrow_mut().scheme_event()?;
-        Ok(None)
-    }).expect("tcpd: failed to listen to events on :tcp");
+        // The unacknowledged data is thrown away and the peer reset
+        assert_eq!(tcpd.close(id).ok(), Some(0));
+        let reset = sent(&mut tcpd);
+        assert_eq!(reset.len(), 1);
+        assert_eq!(reset[0].header.flags.get() & 0xFFF, TCP_RST | TCP_ACK);
+        assert_eq!(reset[0].header.sequence.get(), snd_nxt);
+        assert_eq!(reset[0].header.ack_num.get(), rcv_nxt);
+        assert!(tcpd.handles.is_empty());
+        assert!(tcpd.ports.is_empty());
+    }
 
-    event_queue.trigger_all(0).expect("tcpd: failed to trigger event queue");
+    #[test]
+    fn linger_timed() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+        linger(&mut tcpd, id, Linger { enabled: 1, seconds: 2 });
 
-    event_queue.run().expect("tcpd: failed to run event queue");
-}
+        // The close waits, queued as scheme_event does
+        assert_eq!(tcpd.close(id).err().map(|err| err.errno), Some(EWOULDBLOCK));
+        tcp_handle(&mut tcpd, id).todo_close.push_back(Packet { id: 7, a: syscall::number::SYS_CLOSE, b: id, ..Packet::default() });
+        sent(&mut tcpd);
+        let time = monotonic().ok().unwrap();
 
-fn main() {
-    let time_path = format!("time:{}", CLOCK_MONOTONIC);
-    match syscall::open(&time_path, O_RDWR) {
-        Ok(time_fd) => {
-            println!("tcpd: opening ip:6");
-            match syscall::open("ip:6", O_RDWR | O_NONBLOCK) {
-                Ok(tcp_fd) => {
-                    // Daemonize
-                    if unsafe { syscall::clone(0).unwrap() } == 0 {
-                        println!("tcpd: providing tcp:");
-                        match syscall::open(":tcp", O_RDWR | O_CREAT | O_NONBLOCK) {
-                            Ok(scheme_fd) => {
-                                daemon(scheme_fd, tcp_fd, time_fd);
-                            },
-                            Err(err) => {
-                                println!("tcpd: failed to create tcp scheme: {}", err);
-                                process::exit(1);
-                            }
-                        }
-                    }
-                },
-                Err(err) => {
-                    println!("tcpd: failed to open ip:6: {}", err);
-                    process::exit(1);
-                }
+        // The peer acknowledges only part of it in time
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, start + 4, &[]));
+        assert!(answers(&mut tcpd).is_empty());
+
+        at(&mut tcpd, add_time(&time, &millis(2000)));
+        let reset = sent(&mut tcpd);
+        assert_eq!(reset.last().map(|tcp| tcp.header.flags.get() & 0xFFF), Some(TCP_RST | TCP_ACK));
+        let closed = answers(&mut tcpd);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].id, 7);
+        assert_eq!(closed[0].a, 0);
+        assert!(tcpd.handles.is_empty());
+
+        // A peer that acknowledges everything in time lets the close finish normally
+        let id = established(&mut tcpd);
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+        linger(&mut tcpd, id, Linger { enabled: 1, seconds: 2 });
+        assert_eq!(tcpd.close(id).err().map(|err| err.errno), Some(EWOULDBLOCK));
+        tcp_handle(&mut tcpd, id).todo_close.push_back(Packet { id: 8, a: syscall::number::SYS_CLOSE, b: id, ..Packet::default() });
+        sent(&mut tcpd);
+
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, start + 15, &[]));
+        let closed = answers(&mut tcpd);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].id, 8);
+        assert_eq!(closed[0].a, 0);
+        assert_eq!(tcp_handle(&mut tcpd, id).state, State::FinWait2);
+    }
+
+    #[test]
+    fn fsync_reset() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        assert_eq!(write(&mut tcpd, id, b"unacknowledged").ok(), Some(14));
+        assert_eq!(tcpd.fsync(id).err().map(|err| err.errno), Some(EWOULDBLOCK));
+
+        let (snd_nxt, rcv_nxt) = {
+            let handle = tcp_handle(&mut tcpd, id);
+            (handle.snd_nxt, handle.rcv_nxt)
+        };
+        deliver(&mut tcpd, from_peer(TCP_RST | TCP_ACK, rcv_nxt, snd_nxt, &[]));
+
+        assert_eq!(tcpd.fsync(id).err().map(|err| err.errno), Some(ECONNRESET));
+        assert_eq!(tcpd.fsync(id).err().map(|err| err.errno), Some(EPIPE));
+    }
+
+    #[test]
+    fn fsync_after_last_ack() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        assert_eq!(tcpd.fsync(id).ok(), Some(0));
+
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+        assert_eq!(write(&mut tcpd, id, &[0; 500]).ok(), Some(500));
+        assert_eq!(sent(&mut tcpd).len(), 1);
+        assert_eq!(tcpd.fsync(id).err().map(|err| err.errno), Some(EWOULDBLOCK));
+        tcp_handle(&mut tcpd, id).todo_sync.push_back(Packet { id: 7, a: syscall::number::SYS_FSYNC, b: id, ..Packet::default() });
+
+        // Every byte but the last is not enough
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, start + 499, &[]));
+        assert!(answers(&mut tcpd).is_empty());
+        assert_eq!(tcpd.fsync(id).err().map(|err| err.errno), Some(EWOULDBLOCK));
+
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, start + 500, &[]));
+        let synced = answers(&mut tcpd);
+        assert_eq!(synced.len(), 1);
+        assert_eq!(synced[0].id, 7);
+        assert_eq!(synced[0].a, 0);
+        assert_eq!(tcpd.fsync(id).ok(), Some(0));
+    }
+
+    #[test]
+    fn receiver_sws_drain() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        fill_window(&mut tcpd, id);
+        let threshold = cmp::min(local_mss() as u32, RECEIVE_BUFFER as u32 / 2);
+        let max_window = tcp_handle(&mut tcpd, id).max_window();
+
+        // Emptying the whole buffer a byte at a time only ever opens the window by the threshold,
+        // so what is left over below it at the end is not advertised yet
+        let mut buf = [0; 1];
+        let mut window = 0;
+        let mut updates = 0;
+        while ! tcp_handle(&mut tcpd, id).data.is_empty() {
+            assert_eq!(tcpd.read(id, &mut buf).ok(), Some(1));
+            for tcp in sent(&mut tcpd) {
+                let advertised = tcp.header.window_size.get() as u32;
+                assert_eq!(advertised, window + threshold);
+                window = advertised;
+                updates += 1;
             }
-        },
-        Err(err) => {
-            println!("tcpd: failed to open {}: {}", time_path, err);
-            process::exit(1);
         }
+        assert_eq!(updates, max_window / threshold);
+        assert_eq!(window, max_window - max_window % threshold);
+    }
+
+    #[test]
+    fn listener_close() {
+        let mut tcpd = tcpd();
+        let listener = tcpd.open(b"/10.0.0.1:5000", O_RDWR, 0, 0).ok().unwrap();
+        for port in [1000, 1001].iter() {
+            let (ip, mut tcp) = from_peer(TCP_SYN, PEER_ISN, 0, &[]);
+            tcp.header.src = n16::new(*port);
+            deliver(&mut tcpd, (ip, tcp));
+        }
+        assert_eq!(tcp_handle(&mut tcpd, listener).data.len(), 2);
+
+        // An accept that is blocked, queued as scheme_event does
+        tcp_handle(&mut tcpd, listener).todo_dup.push_back(Packet { id: 7, a: syscall::number::SYS_DUP, b: listener, ..Packet::default() });
+
+        // Both SYNs are refused rather than left to time out, and the accept fails
+        assert_eq!(tcpd.close(listener).ok(), Some(0));
+        let resets = sent(&mut tcpd);
+        assert_eq!(resets.len(), 2);
+        for (reset, port) in resets.iter().zip([1000, 1001].iter()) {
+            assert_eq!(reset.header.flags.get() & 0xFFF, TCP_RST | TCP_ACK);
+            assert_eq!(reset.header.dst.get(), *port);
+            assert_eq!(reset.header.ack_num.get(), PEER_ISN + 1);
+        }
+
+        let aborted = answers(&mut tcpd);
+        assert_eq!(aborted.len(), 1);
+        assert_eq!(aborted[0].id, 7);
+        assert_eq!(aborted[0].a, (-ECONNABORTED) as usize);
+
+        assert!(tcpd.handles.is_empty());
+        assert!(tcpd.ports.is_empty());
+        assert_eq!(tcpd.half_open, 0);
+    }
+
+    #[test]
+    fn sender_sws() {
+        let mut tcpd = tcpd();
+        let id = established(&mut tcpd);
+        let start = tcp_handle(&mut tcpd, id).snd_nxt;
+        tcp_handle(&mut tcpd, id).nodelay = true;
+
+        // The peer's window shrinks far below half of what it offered before
+        deliver(&mut tcpd, with_window(from_peer(TCP_ACK, PEER_ISN + 1, start, &[]), 100));
+        assert_eq!(tcp_handle(&mut tcpd, id).snd_wnd, 100);
+        assert_eq!(tcp_handle(&mut tcpd, id).max_snd_wnd, 65535);
+
+        // So a write that does not fit is held, even with nodelay, rather than sent as a tinygram
+        assert_eq!(write(&mut tcpd, id, &[0; 500]).ok(), Some(500));
+        assert!(sent(&mut tcpd).is_empty());
+
+        // Until the persist timer gives up waiting and sends what fits
+        at(&mut tcpd, add_time(&monotonic().ok().unwrap(), &millis(RTO_MAX)));
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].header.sequence.get(), start);
+        assert_eq!(segments[0].data.len(), 100);
+
+        // An ACK opening the window lets the rest go
+        deliver(&mut tcpd, from_peer(TCP_ACK, PEER_ISN + 1, start + 100, &[]));
+        let segments = sent(&mut tcpd);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].data.len(), 400);
     }
 }
@@ -0,0 +1,244 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use syscall::data::TimeSpec;
+
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum TimerKind {
+    /// Retransmit the oldest unacknowledged segment
+    Retransmit,
+    /// Send an ACK that was held back for received data
+    DelayedAck,
+    /// Release a connection that has finished TimeWait
+    TimeWait,
+    /// Drop a half closed connection whose peer never sent a FIN
+    FinWait2,
+    /// Reset a closed connection whose data was not acknowledged in time
+    Linger,
+    /// Probe a peer that has closed its window
+    Persist,
+    /// Probe an idle connection to check that the peer is still there
+    KeepAlive,
+}
+
+type Deadline = (i64, i32);
+
+fn deadline(time: &TimeSpec) -> Deadline {
+    (time.tv_sec, time.tv_nsec)
+}
+
+/// Per-handle timers, ordered by deadline
+pub struct Timers {
+    queue: BTreeSet<(Deadline, usize, TimerKind)>,
+    armed: BTreeMap<(usize, TimerKind), Deadline>,
+    scheduled: Option<Deadline>,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Timers {
+            queue: BTreeSet::new(),
+            armed: BTreeMap::new(),
+            scheduled: None,
+        }
+    }
+
+    /// Arm a timer, replacing any deadline it already had
+    pub fn arm(&mut self, file: usize, kind: TimerKind, time: TimeSpec) {
+        self.cancel(file, kind);
+        self.queue.insert((deadline(&time), file, kind));
+        self.armed.insert((file, kind), deadline(&time));
+    }
+
+    pub fn cancel(&mut self, file: usize, kind: TimerKind) {
+        if let Some(deadline) = self.armed.remove(&(file, kind)) {
+            self.queue.remove(&(deadline, file, kind));
+        }
+    }
+
+    /// Arm a timer if a deadline is given, otherwise cancel it
+    pub fn set(&mut self, file: usize, kind: TimerKind, time: Option<TimeSpec>) {
+        match time {
+            Some(time) => self.arm(file, kind, time),
+            None => self.cancel(file, kind)
+        }
+    }
+
+    /// Arm a timer unless it is already running if a deadline is given, otherwise cancel it
+    pub fn start(&mut self, file: usize, kind: TimerKind, time: Option<TimeSpec>) {
+        match time {
+            Some(time) => if ! self.armed.contains_key(&(file, kind)) {
+                self.arm(file, kind, time);
+            },
+            None => self.cancel(file, kind)
+        }
+    }
+
+    /// Cancel every timer belonging to a handle
+    pub fn cancel_all(&mut self, file: usize) {
+        let kinds: Vec<TimerKind> = self.armed.keys()
+            .filter(|&&(timer_file, _)| timer_file == file)
+            .map(|&(_, kind)| kind)
+            .collect();
+
+        for kind in kinds {
+            self.cancel(file, kind);
+        }
+    }
+
+    /// Remove every timer that has reached its deadline, earliest first
+    pub fn expire(&mut self, time: &TimeSpec) -> Vec<(usize, TimerKind)> {
+        let now = deadline(time);
+
+        if self.scheduled.is_some_and(|scheduled| scheduled <= now) {
+            self.scheduled = None;
+        }
+
+        let mut expired = Vec::new();
+        while let Some(&(timer_deadline, file, kind)) = self.queue.iter().next() {
+            if timer_deadline > now {
+                break;
+            }
+            self.queue.remove(&(timer_deadline, file, kind));
+            self.armed.remove(&(file, kind));
+            expired.push((file, kind));
+        }
+        expired
+    }
+
+    /// The earliest deadline, if the time: scheme has not already been asked to wake us for it
+    pub fn reschedule(&mut self) -> Option<TimeSpec> {
+        let next = self.queue.iter().next().map(|&(timer_deadline, _, _)| timer_deadline)?;
+
+        if self.scheduled.is_none_or(|scheduled| next < scheduled) {
+            self.scheduled = Some(next);
+            Some(TimeSpec {
+                tv_sec: next.0,
+                tv_nsec: next.1
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(tv_sec: i64, tv_nsec: i32) -> TimeSpec {
+        TimeSpec {
+            tv_sec,
+            tv_nsec
+        }
+    }
+
+    #[test]
+    fn expire_earliest_first() {
+        let mut timers = Timers::new();
+        timers.arm(1, TimerKind::Retransmit, at(3, 0));
+        timers.arm(2, TimerKind::Retransmit, at(1, 500));
+        timers.arm(3, TimerKind::Retransmit, at(1, 0));
+
+        assert_eq!(timers.expire(&at(2, 0)), vec![(3, TimerKind::Retransmit), (2, TimerKind::Retransmit)]);
+        assert_eq!(timers.expire(&at(2, 0)), vec![]);
+        assert_eq!(timers.expire(&at(3, 0)), vec![(1, TimerKind::Retransmit)]);
+    }
+
+    #[test]
+    fn expire_together() {
+        let mut timers = Timers::new();
+        timers.arm(1, TimerKind::Retransmit, at(1, 0));
+        timers.arm(2, TimerKind::Retransmit, at(1, 0));
+        timers.arm(3, TimerKind::Retransmit, at(1, 0));
+        timers.arm(4, TimerKind::Retransmit, at(5, 0));
+
+        let expired = timers.expire(&at(1, 0));
+        assert_eq!(expired.len(), 3);
+        assert!(expired.contains(&(1, TimerKind::Retransmit)));
+        assert!(expired.contains(&(2, TimerKind::Retransmit)));
+        assert!(expired.contains(&(3, TimerKind::Retransmit)));
+        assert_eq!(timers.expire(&at(4, 999999999)), vec![]);
+    }
+
+    #[test]
+    fn arm_replaces_deadline() {
+        let mut timers = Timers::new();
+        timers.arm(1, TimerKind::Retransmit, at(1, 0));
+        timers.arm(1, TimerKind::Retransmit, at(5, 0));
+
+        assert_eq!(timers.expire(&at(4, 0)), vec![]);
+        assert_eq!(timers.expire(&at(5, 0)), vec![(1, TimerKind::Retransmit)]);
+        assert_eq!(timers.expire(&at(10, 0)), vec![]);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut timers = Timers::new();
+        timers.arm(1, TimerKind::Retransmit, at(1, 0));
+        timers.arm(2, TimerKind::Retransmit, at(1, 0));
+        timers.cancel(1, TimerKind::Retransmit);
+        // Cancelling a timer that is not running does nothing
+        timers.cancel(3, TimerKind::Retransmit);
+
+        assert_eq!(timers.expire(&at(1, 0)), vec![(2, TimerKind::Retransmit)]);
+    }
+
+    #[test]
+    fn cancel_all() {
+        let mut timers = Timers::new();
+        timers.arm(1, TimerKind::Retransmit, at(1, 0));
+        timers.arm(2, TimerKind::Retransmit, at(3, 0));
+        timers.cancel_all(1);
+
+        assert_eq!(timers.expire(&at(3, 0)), vec![(2, TimerKind::Retransmit)]);
+    }
+
+    #[test]
+    fn start_keeps_running_timer() {
+        let mut timers = Timers::new();
+        timers.start(1, TimerKind::Persist, Some(at(1, 0)));
+        timers.start(1, TimerKind::Persist, Some(at(5, 0)));
+        assert_eq!(timers.expire(&at(1, 0)), vec![(1, TimerKind::Persist)]);
+
+        // Once it has expired it can be started again
+        timers.start(1, TimerKind::Persist, Some(at(5, 0)));
+        assert_eq!(timers.expire(&at(4, 0)), vec![]);
+
+        timers.start(1, TimerKind::Persist, None);
+        assert_eq!(timers.expire(&at(5, 0)), vec![]);
+    }
+
+    #[test]
+    fn set_rearms() {
+        let mut timers = Timers::new();
+        timers.set(1, TimerKind::Retransmit, Some(at(1, 0)));
+        timers.set(1, TimerKind::Retransmit, Some(at(2, 0)));
+        assert_eq!(timers.expire(&at(1, 0)), vec![]);
+
+        timers.set(1, TimerKind::Retransmit, None);
+        assert_eq!(timers.expire(&at(2, 0)), vec![]);
+    }
+
+    #[test]
+    fn reschedule() {
+        let mut timers = Timers::new();
+        assert!(timers.reschedule().is_none());
+
+        timers.arm(1, TimerKind::Retransmit, at(5, 0));
+        assert_eq!(timers.reschedule().map(|time| (time.tv_sec, time.tv_nsec)), Some((5, 0)));
+
+        // A later deadline does not need another wakeup while the earlier one is scheduled
+        timers.arm(2, TimerKind::Retransmit, at(7, 0));
+        assert!(timers.reschedule().is_none());
+        timers.cancel(1, TimerKind::Retransmit);
+        assert!(timers.reschedule().is_none());
+
+        // An earlier one does
+        timers.arm(3, TimerKind::Retransmit, at(2, 0));
+        assert_eq!(timers.reschedule().map(|time| (time.tv_sec, time.tv_nsec)), Some((2, 0)));
+
+        // Once the scheduled wakeup has passed, the next deadline is scheduled again
+        assert_eq!(timers.expire(&at(2, 0)), vec![(3, TimerKind::Retransmit)]);
+        assert_eq!(timers.reschedule().map(|time| (time.tv_sec, time.tv_nsec)), Some((7, 0)));
+    }
+}